@@ -10,10 +10,12 @@
 use rand;
 use rand::Rng;
 
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt::{self, Debug};
 use std::hash::Hash;
 use std::net;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use rustc_serialize as serialize;
 use rustc_serialize::hex::FromHex;
@@ -25,7 +27,23 @@ pub trait GenericId: Hash + PartialEq + Eq + Ord + Clone + Send + Sync + Debug {
     fn is_zero(&self) -> bool;
     fn bits(&self) -> usize;
     /// num::bigint::RandBigInt::gen_biguint
-    fn gen(bit_size: usize) -> Self;
+    fn gen(bit_size: usize) -> Self {
+        Self::gen_with(bit_size, &mut rand::thread_rng())
+    }
+    /// Random ID of at most `bit_size` bits taken from `rng`.
+    fn gen_with<R: Rng>(bit_size: usize, rng: &mut R) -> Self;
+    /// Value of bit number `index`, counting from the least significant one.
+    fn bit(&self, index: usize) -> bool;
+    /// ID sharing bits above `bit` with this one and differing in `bit`.
+    ///
+    /// Bits are numbered from the least significant one, lower bits are
+    /// taken from `lower`. The distance between the result and this ID
+    /// always has exactly `bit + 1` bits.
+    fn diverge_at(&self, bit: usize, lower: &Self) -> Self;
+    /// Number of bits in every ID of this type, `None` if not fixed.
+    fn max_bits() -> Option<usize> {
+        None
+    }
 
     fn encode<S: serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error>;
     fn decode<D: serialize::Decoder>(d: &mut D) -> Result<Self, D::Error>;
@@ -41,14 +59,25 @@ impl GenericId for u64 {
     fn bits(&self) -> usize {
         (64 - self.leading_zeros()) as usize
     }
-    fn gen(bit_size: usize) -> u64 {
+    fn gen_with<R: Rng>(bit_size: usize, rng: &mut R) -> u64 {
         assert!(bit_size <= 64);
         if bit_size == 64 {
-            rand::thread_rng().gen()
+            rng.gen()
         } else {
-            rand::thread_rng().gen_range(0, 1 << bit_size)
+            rng.gen_range(0, 1 << bit_size)
         }
     }
+    fn bit(&self, index: usize) -> bool {
+        index < 64 && (self >> index) & 1 == 1
+    }
+    fn diverge_at(&self, bit: usize, lower: &u64) -> u64 {
+        assert!(bit < 64);
+        let mask = (1u64 << bit) - 1;
+        ((self ^ (1 << bit)) & !mask) | (lower & mask)
+    }
+    fn max_bits() -> Option<usize> {
+        Some(64)
+    }
 
     fn encode<S: serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
         s.emit_str(&format!("{:x}", self))
@@ -87,23 +116,31 @@ impl GenericId for Vec<u8> {
         assert!(bits == 0);
         0
     }
-    fn gen(bit_size: usize) -> Vec<u8> {
+    fn gen_with<R: Rng>(bit_size: usize, rng: &mut R) -> Vec<u8> {
         let nb_full_digits = bit_size / 8;
         let nb_bits_partial_digit = bit_size % 8;
-        let mut rng = rand::thread_rng();
         if nb_bits_partial_digit == 0 {
             let mut res = vec![0u8; nb_full_digits];
             rng.fill(&mut res[..]);
             res
         } else {
             let mut res = vec![0u8; nb_full_digits + 1];
-            let first_digit = rng.gen_range(0, 1 << (nb_bits_partial_digit - 1));
+            let first_digit = rng.gen_range(0, 1 << nb_bits_partial_digit);
             res[0] = first_digit;
             rng.fill(&mut res[1..nb_full_digits + 1]);
             res
         }
     }
 
+    fn bit(&self, index: usize) -> bool {
+        bytes_bit(self, index)
+    }
+    fn diverge_at(&self, bit: usize, lower: &Vec<u8>) -> Vec<u8> {
+        let mut result = self.clone();
+        diverge_bytes(&mut result, bit, lower);
+        result
+    }
+
     fn encode<S: serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
         s.emit_str(&self.to_hex())
     }
@@ -119,6 +156,34 @@ impl GenericId for Vec<u8> {
     }
 }
 
+/// Implementation of `GenericId::bit` for big-endian bytes.
+pub(crate) fn bytes_bit(id: &[u8], index: usize) -> bool {
+    match id.len().checked_sub(index / 8 + 1) {
+        Some(pos) => (id[pos] >> (index % 8)) & 1 == 1,
+        None => false,
+    }
+}
+
+/// Implementation of `GenericId::diverge_at` for big-endian bytes.
+///
+/// `lower` is aligned to the least significant byte of `id`.
+pub(crate) fn diverge_bytes(id: &mut [u8], bit: usize, lower: &[u8]) {
+    assert!(bit < id.len() * 8);
+    for (i, digit) in id.iter_mut().rev().enumerate().take(bit / 8 + 1) {
+        let lower_digit = if i < lower.len() {
+            lower[lower.len() - 1 - i]
+        } else {
+            0
+        };
+        if i < bit / 8 {
+            *digit = lower_digit;
+        } else {
+            let mask = (1u8 << (bit % 8)) - 1;
+            *digit = ((*digit ^ (1 << (bit % 8))) & !mask) | (lower_digit & mask);
+        }
+    }
+}
+
 /// Trait representing table with known nodes.
 ///
 /// Keeps some reasonable subset of known nodes passed to `update`.
@@ -128,14 +193,91 @@ where
 {
     /// Generate suitable random ID.
     fn random_id(&self) -> TId;
+    /// Number of bits in IDs, i.e. the number of k-buckets.
+    fn hash_size(&self) -> usize;
+    /// Maximum number of nodes in a k-bucket, i.e. k in Kademlia terms.
+    fn bucket_size(&self) -> usize;
     /// Store or update node in the table.
     fn update(&mut self, node: &Node<TId, TAddr>) -> bool;
+    /// Store or update node in the table as seen at time `now`.
+    fn update_at(&mut self, node: &Node<TId, TAddr>, _now: Instant) -> bool {
+        self.update(node)
+    }
+    /// Store or update node seen at time `now`, reporting why it was not
+    /// stored.
+    ///
+    /// Defaults to `UpdateError::BucketFull` if `update_at` fails.
+    fn try_update_at(&mut self, node: &Node<TId, TAddr>, now: Instant) -> Result<(), UpdateError> {
+        if self.update_at(node, now) {
+            Ok(())
+        } else {
+            Err(UpdateError::BucketFull)
+        }
+    }
     /// Find given number of node, closest to given ID.
     fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>>;
-    /// Pop expired or the oldest nodes from table for inspection.
-    fn pop_oldest(&mut self) -> Vec<Node<TId, TAddr>>;
+    /// Pop nodes expired by `now` or the oldest nodes for inspection.
+    fn pop_oldest(&mut self, now: Instant) -> Vec<Node<TId, TAddr>>;
+    /// Remove a node from the table, returning it if it was known.
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>>;
+    /// Get a known node by its ID.
+    fn get(&self, id: &TId) -> Option<&Node<TId, TAddr>>;
+    /// Iterate over all known nodes.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Node<TId, TAddr>> + 'a>;
+    /// Check whether a node is known.
+    fn contains(&self, id: &TId) -> bool {
+        self.get(id).is_some()
+    }
+    /// Number of known nodes.
+    fn len(&self) -> usize {
+        self.iter().count()
+    }
+    /// Check whether no nodes are known.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Record that a node answered our request within `rtt` at time `now`.
+    fn record_response(&mut self, _id: &TId, _rtt: Duration, _now: Instant) {}
+    /// Record that a node failed to answer our request.
+    fn record_failure(&mut self, _id: &TId) {}
+    /// Record a lookup of the given ID at time `now`.
+    fn record_lookup(&mut self, _id: &TId, _now: Instant) {}
+    /// Get IDs to look up to refresh parts of the table.
+    ///
+    /// Returns a random ID, generated with `rng`, for every part of the ID
+    /// space that has not seen a lookup within `interval` before `now`.
+    fn stale_buckets<R: Rng>(&self, _now: Instant, _interval: Duration, _rng: &mut R) -> Vec<TId> {
+        Vec::new()
+    }
+}
+
+/// Reason for a node not being added to the table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateError {
+    /// The k-bucket is full, the node is cached as a replacement.
+    BucketFull,
+    /// Too many nodes from the same network in the k-bucket.
+    BucketSubnetLimit,
+    /// Too many nodes from the same network in the table.
+    TableSubnetLimit,
 }
 
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UpdateError::BucketFull => write!(f, "k-bucket is full"),
+            UpdateError::BucketSubnetLimit => {
+                write!(f, "too many nodes from the same network in the k-bucket")
+            }
+            UpdateError::TableSubnetLimit => {
+                write!(f, "too many nodes from the same network in the table")
+            }
+        }
+    }
+}
+
+impl Error for UpdateError {}
+
 /// Structure representing a node in system.
 ///
 /// Every node has an address (IP and port) and a numeric ID, which is
@@ -209,10 +351,7 @@ where
             })?;
 
             let id = d.read_struct_field("id", 1, TId::decode)?;
-            Ok(Node {
-                address: addr,
-                id: id,
-            })
+            Ok(Node { address: addr, id })
         })
     }
 }
@@ -220,19 +359,40 @@ where
 #[cfg(test)]
 mod test {
     use rustc_serialize::json;
+    use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
     use std::net;
 
-    use super::{GenericAPI, Node};
+    use super::{GenericAPI, GenericId, Node};
 
     use super::super::utils::test;
     type TestsIdType = test::IdType;
 
-    #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+    #[derive(Debug, Clone)]
     struct SimplifiedNode {
         address: String,
         id: String,
     }
 
+    impl Encodable for SimplifiedNode {
+        fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+            s.emit_struct("SimplifiedNode", 2, |s| {
+                s.emit_struct_field("address", 0, |s2| self.address.encode(s2))?;
+                s.emit_struct_field("id", 1, |s2| self.id.encode(s2))
+            })
+        }
+    }
+
+    impl Decodable for SimplifiedNode {
+        fn decode<D: Decoder>(d: &mut D) -> Result<SimplifiedNode, D::Error> {
+            d.read_struct("SimplifiedNode", 2, |d| {
+                Ok(SimplifiedNode {
+                    address: d.read_struct_field("address", 0, Decodable::decode)?,
+                    id: d.read_struct_field("id", 1, Decodable::decode)?,
+                })
+            })
+        }
+    }
+
     struct DummyAPI {
         value: Option<i32>,
     }
@@ -324,4 +484,41 @@ mod test {
             assert!(res);
         });
     }
+
+    #[test]
+    fn test_gen_vec_partial_digit() {
+        // The highest bit must be reachable
+        let max = (0..100)
+            .map(|_| <Vec<u8> as GenericId>::gen(3).bits())
+            .max()
+            .unwrap();
+        assert_eq!(3, max);
+    }
+
+    #[test]
+    fn test_diverge_at_u64() {
+        let id: u64 = 0b1011_0110;
+        assert_eq!(0b1010_0001, id.diverge_at(4, &0xffff_0001));
+        assert_eq!(0b1011_0111, id.diverge_at(0, &0));
+        for bit in 0..64 {
+            assert_eq!(bit + 1, id.diverge_at(bit, &u64::MAX).bitxor(&id).bits());
+        }
+    }
+
+    #[test]
+    fn test_diverge_at_vec() {
+        let id = vec![0b1011_0110, 0b1111_0000, 0x42];
+        assert_eq!(
+            vec![0b1011_0110, 0b1110_1111, 0xff],
+            id.diverge_at(12, &vec![0xff, 0xff])
+        );
+        assert_eq!(
+            vec![0b1011_0110, 0b1111_0000, 0x43],
+            id.diverge_at(0, &vec![0])
+        );
+        for bit in 0..24 {
+            let other = id.diverge_at(bit, &vec![0xa5; 3]);
+            assert_eq!(bit + 1, other.bitxor(&id).bits());
+        }
+    }
 }
@@ -0,0 +1,240 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Fixed-size identifiers.
+//!
+//! Unlike `Vec<u8>`, these are stored on the stack and never have
+//! mismatching lengths.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use rand::Rng;
+use rustc_serialize as serialize;
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use super::base::{bytes_bit, diverge_bytes};
+use super::GenericId;
+
+/// Error returned when parsing an ID from a string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseIdError(String);
+
+impl fmt::Display for ParseIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid ID: {}", self.0)
+    }
+}
+
+impl Error for ParseIdError {}
+
+fn parse_hex(s: &str, size: usize) -> Result<Vec<u8>, ParseIdError> {
+    let bytes = s.from_hex().map_err(|e| ParseIdError(format!("{}", e)))?;
+    if bytes.len() != size {
+        return Err(ParseIdError(format!(
+            "expected {} bytes, got {}",
+            size,
+            bytes.len()
+        )));
+    }
+    Ok(bytes)
+}
+
+macro_rules! fixed_id {
+    ($name:ident, $size:expr, $doc:expr) => {
+        #[doc = $doc]
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+        pub struct $name([u8; $size]);
+
+        impl $name {
+            /// Size of the ID in bytes.
+            pub const BYTES: usize = $size;
+
+            /// Create an ID from a slice of exactly `BYTES` bytes.
+            pub fn from_slice(bytes: &[u8]) -> Option<$name> {
+                if bytes.len() != $size {
+                    return None;
+                }
+                let mut result = [0u8; $size];
+                result.copy_from_slice(bytes);
+                Some($name(result))
+            }
+
+            /// Raw bytes of the ID, most significant first.
+            pub fn as_bytes(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl From<[u8; $size]> for $name {
+            fn from(bytes: [u8; $size]) -> $name {
+                $name(bytes)
+            }
+        }
+
+        impl GenericId for $name {
+            fn bitxor(&self, other: &$name) -> $name {
+                let mut result = [0u8; $size];
+                for (digit, (digit1, digit2)) in result.iter_mut().zip(self.0.iter().zip(&other.0))
+                {
+                    *digit = digit1 ^ digit2;
+                }
+                $name(result)
+            }
+            fn is_zero(&self) -> bool {
+                self.0.iter().all(|digit| *digit == 0)
+            }
+            fn bits(&self) -> usize {
+                match self.0.iter().position(|digit| *digit != 0) {
+                    Some(pos) => ($size - pos) * 8 - self.0[pos].leading_zeros() as usize,
+                    None => 0,
+                }
+            }
+            fn gen_with<R: Rng>(bit_size: usize, rng: &mut R) -> $name {
+                assert!(bit_size <= $size * 8);
+                let mut result = [0u8; $size];
+                rng.fill(&mut result[..]);
+                let zero_bits = $size * 8 - bit_size;
+                for digit in result.iter_mut().take(zero_bits / 8) {
+                    *digit = 0;
+                }
+                if zero_bits % 8 != 0 {
+                    result[zero_bits / 8] &= 0xff >> (zero_bits % 8);
+                }
+                $name(result)
+            }
+            fn bit(&self, index: usize) -> bool {
+                bytes_bit(&self.0, index)
+            }
+            fn diverge_at(&self, bit: usize, lower: &$name) -> $name {
+                let mut result = self.0;
+                diverge_bytes(&mut result, bit, &lower.0);
+                $name(result)
+            }
+            fn max_bits() -> Option<usize> {
+                Some($size * 8)
+            }
+
+            fn encode<S: serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+                s.emit_str(&self.0.to_hex())
+            }
+            fn decode<D: serialize::Decoder>(d: &mut D) -> Result<$name, D::Error> {
+                let s = d.read_str()?;
+                s.parse().map_err(|e: ParseIdError| {
+                    d.error(&format!("Expected hex-encoded ID, got {}, error {}", s, e))
+                })
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(&self.0.to_hex())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseIdError;
+
+            fn from_str(s: &str) -> Result<$name, ParseIdError> {
+                let bytes = parse_hex(s, $size)?;
+                Ok($name::from_slice(&bytes).unwrap())
+            }
+        }
+    };
+}
+
+fixed_id!(Id160, 20, "160-bit ID, as used by the BitTorrent DHT.");
+fixed_id!(Id256, 32, "256-bit ID, e.g. a SHA-256 hash.");
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::json;
+
+    use super::super::{GenericId, GenericNodeTable, KNodeTable};
+    use super::{Id160, Id256};
+
+    static HEX: &str = "000000000000000000000000000000000000f00d";
+
+    #[test]
+    fn test_parse_and_display() {
+        let id: Id160 = HEX.parse().unwrap();
+        assert_eq!(0xf0, id.as_bytes()[18]);
+        assert_eq!(HEX, id.to_string());
+        assert_eq!(format!("Id160({})", HEX), format!("{:?}", id));
+        assert!("f00d".parse::<Id160>().is_err());
+        assert!("xyz".parse::<Id160>().is_err());
+        assert!(HEX.parse::<Id256>().is_err());
+    }
+
+    #[test]
+    fn test_bits() {
+        let id: Id160 = HEX.parse().unwrap();
+        assert_eq!(16, id.bits());
+        assert_eq!(0, Id160::default().bits());
+        assert!(Id160::default().is_zero());
+        let id = Id256::from([0xff; 32]);
+        assert_eq!(256, id.bits());
+        assert!(id.bitxor(&id).is_zero());
+    }
+
+    #[test]
+    fn test_gen() {
+        for bit_size in &[0, 1, 7, 8, 9, 100, 160] {
+            for _ in 0..10 {
+                assert!(Id160::gen(*bit_size).bits() <= *bit_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let id: Id160 = HEX.parse().unwrap();
+        let mut encoder_out = String::new();
+        {
+            let mut encoder = json::Encoder::new(&mut encoder_out);
+            GenericId::encode(&id, &mut encoder).unwrap();
+        }
+        assert_eq!(format!("\"{}\"", HEX), encoder_out);
+        let mut decoder = json::Decoder::new(json::Json::from_str(&encoder_out).unwrap());
+        let decoded: Id160 = GenericId::decode(&mut decoder).unwrap();
+        assert_eq!(id, decoded);
+    }
+
+    #[test]
+    fn test_table() {
+        let mut table = KNodeTable::<Id160, ()>::new(Id160::default());
+        let id: Id160 = HEX.parse().unwrap();
+        assert!(table.update(&super::super::Node { id, address: () }));
+        assert_eq!(
+            vec![id],
+            table
+                .find(&id, 1)
+                .into_iter()
+                .map(|n| n.id)
+                .collect::<Vec<_>>()
+        );
+        assert!(table.random_id().bits() <= 160);
+    }
+
+    #[test]
+    fn test_diverge_at() {
+        let id: Id160 = HEX.parse().unwrap();
+        for bit in 0..160 {
+            let other = id.diverge_at(bit, &Id160::gen(160));
+            assert_eq!(bit + 1, other.bitxor(&id).bits());
+        }
+    }
+}
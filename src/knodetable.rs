@@ -10,21 +10,38 @@
 //! DHT node table implementation based on Kademlia.
 //!
 //! See [original paper](http://pdos.csail.mit.edu/%7Epetar/papers/maymounkov-kademlia-lncs.pdf)
-//! for details. The most essential difference is that when k-bucket is full,
-//! no RPC call is done. It is up to upper-level code to ensure proper clean up
-//! using `pop_oldest` call.
+//! for details. When a k-bucket is full, newcomers are put into its
+//! replacement cache and no RPC call is done by the table itself. It is up to
+//! upper-level code (e.g. `service::Handler::ping_oldest`) to check the nodes
+//! returned by `pop_oldest` and to update the alive ones again or to report
+//! the dead ones with `record_failure`. Only one node per k-bucket is checked
+//! at a time.
 
 use std::cmp;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
+use std::net;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use rustc_serialize as serialize;
 
 use super::GenericId;
 use super::GenericNodeTable;
 use super::Node;
 
+pub use super::base::UpdateError;
+
 // TODO(divius): make public?
-static BUCKET_SIZE: usize = 32;
-static DEFAULT_HASH_SIZE: usize = 64;
+pub(crate) static BUCKET_SIZE: usize = 32;
+pub(crate) static DEFAULT_HASH_SIZE: usize = 160;
+/// Nodes not heard from for this time are popped by `pop_oldest`.
+pub(crate) static DEFAULT_NODE_TTL: Duration = Duration::from_secs(15 * 60);
+/// Version of the snapshot format written by `Encodable`.
+static SNAPSHOT_VERSION: u32 = 1;
+/// Nodes failing this many requests in a row are popped by `pop_oldest`
+/// even from k-buckets that did not reject newcomers.
+pub(crate) static MAX_FAILURES: u32 = 3;
 
 /// Kademlia node table.
 ///
@@ -37,14 +54,83 @@ static DEFAULT_HASH_SIZE: usize = 64;
 pub struct KNodeTable<TId, TAddr> {
     this_id: TId,
     hash_size: usize,
+    node_ttl: Option<Duration>,
+    subnets: Option<Subnets<TAddr>>,
     // TODO(divius): convert to more appropriate data structure
     buckets: Vec<KBucket<TId, TAddr>>,
 }
 
 /// K-bucket - structure for keeping last nodes in Kademlia.
+///
+/// Nodes that do not fit into a full k-bucket are kept in a replacement
+/// cache of the same size. When the oldest node is popped for inspection,
+/// the most recently seen replacement takes its place. If the popped node
+/// is updated again (i.e. it is still alive), the replacement goes back to
+/// the cache. Until then, or until a failure of the popped node is recorded,
+/// the k-bucket is not checked again.
 pub struct KBucket<TId, TAddr> {
     data: VecDeque<Node<TId, TAddr>>,
     size: usize,
+    last_lookup: Instant,
+    info: HashMap<TId, NodeInfo>,
+    replacements: VecDeque<Node<TId, TAddr>>,
+    // IDs of the popped node being checked and of the replacement promoted
+    // instead, if any
+    checked: Option<(TId, Option<TId>)>,
+    // Whether a newcomer was cached as a replacement since the last pop
+    rejected: bool,
+}
+
+/// Liveness information about a node in a k-bucket.
+#[derive(Clone, Debug)]
+pub struct NodeInfo {
+    /// Last time the node was updated in the table.
+    pub last_seen: Instant,
+    /// Last time the node answered our request.
+    pub last_response: Option<Instant>,
+    /// Number of our requests the node failed to answer in a row.
+    pub failures: u32,
+    /// Smoothed round-trip time of our requests.
+    pub rtt: Option<Duration>,
+}
+
+/// Limits on the number of nodes from the same network.
+///
+/// Networks are IPv4 /24 and IPv6 /64 prefixes of node addresses. Limits
+/// make it harder for an attacker controlling one network to fill the table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SubnetLimits {
+    /// Maximum number of nodes from one network in a k-bucket.
+    pub per_bucket: Option<usize>,
+    /// Maximum number of nodes from one network in the whole table.
+    pub per_table: Option<usize>,
+}
+
+type NetworkFn<TAddr> = fn(&TAddr) -> net::IpAddr;
+
+// Subnet limits with the number of nodes per network in the table
+struct Subnets<TAddr> {
+    limits: SubnetLimits,
+    network: NetworkFn<TAddr>,
+    counts: HashMap<net::IpAddr, usize>,
+}
+
+// Predicate deciding whether a replacement may join a k-bucket with the
+// given nodes
+type AllowedFn<'a, TId, TAddr> = &'a dyn Fn(&VecDeque<Node<TId, TAddr>>, &Node<TId, TAddr>) -> bool;
+
+/// Network of the address: IPv4 /24 or IPv6 /64 prefix.
+fn subnet(addr: &net::SocketAddr) -> net::IpAddr {
+    match *addr {
+        net::SocketAddr::V4(ref addr) => {
+            let o = addr.ip().octets();
+            net::IpAddr::V4(net::Ipv4Addr::new(o[0], o[1], o[2], 0))
+        }
+        net::SocketAddr::V6(ref addr) => {
+            let s = addr.ip().segments();
+            net::IpAddr::V6(net::Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
 }
 
 impl<TId, TAddr> KNodeTable<TId, TAddr>
@@ -55,8 +141,11 @@ where
     /// Create a new node table.
     ///
     /// `this_id` -- ID of the current node (used to calculate metrics).
+    ///
+    /// The hash size is the size of `TId` if it is fixed, 160 otherwise.
     pub fn new(this_id: TId) -> KNodeTable<TId, TAddr> {
-        KNodeTable::new_with_details(this_id, BUCKET_SIZE, DEFAULT_HASH_SIZE)
+        let hash_size = TId::max_bits().unwrap_or(DEFAULT_HASH_SIZE);
+        KNodeTable::new_with_details(this_id, BUCKET_SIZE, hash_size)
     }
 
     pub fn new_with_details(
@@ -65,8 +154,10 @@ where
         hash_size: usize,
     ) -> KNodeTable<TId, TAddr> {
         KNodeTable {
-            this_id: this_id,
-            hash_size: hash_size,
+            this_id,
+            hash_size,
+            node_ttl: Some(DEFAULT_NODE_TTL),
+            subnets: None,
             buckets: (0..hash_size).map(|_| KBucket::new(bucket_size)).collect(),
         }
     }
@@ -74,22 +165,140 @@ where
     pub fn buckets(&self) -> &Vec<KBucket<TId, TAddr>> {
         &self.buckets
     }
+    /// ID of the current node.
+    pub fn this_id(&self) -> &TId {
+        &self.this_id
+    }
+
+    /// Generate a random ID falling into the bucket with the given index.
+    ///
+    /// The distance between the result and our ID has exactly `index + 1`
+    /// bits.
+    pub fn random_id_in_bucket<R: Rng>(&self, index: usize, rng: &mut R) -> TId {
+        assert!(index < self.hash_size);
+        self.this_id
+            .diverge_at(index, &TId::gen_with(self.hash_size, rng))
+    }
+
+    /// Set time after which nodes not heard from expire, `None` to disable.
+    ///
+    /// Defaults to 15 minutes.
+    pub fn set_node_ttl(&mut self, ttl: Option<Duration>) {
+        self.node_ttl = ttl;
+    }
+
+    /// Pop nodes not heard from within the node TTL before `now`.
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<Node<TId, TAddr>> {
+        let ttl = match self.node_ttl {
+            Some(ttl) => ttl,
+            None => return Vec::new(),
+        };
+        (0..self.buckets.len())
+            .flat_map(|index| {
+                self.modify_bucket(index, |b, allowed| b.pop_expired(now, ttl, allowed))
+            })
+            .collect()
+    }
+
+    /// Use settings of `other` (node TTL and subnet limits) for this table,
+    /// e.g. one loaded from a snapshot.
+    ///
+    /// Fails if the tables have different hash or k-bucket sizes.
+    pub(crate) fn apply_settings(&mut self, other: &KNodeTable<TId, TAddr>) -> Result<(), String> {
+        if self.hash_size != other.hash_size {
+            return Err(format!(
+                "Hash size {} does not match the expected {}",
+                self.hash_size, other.hash_size
+            ));
+        }
+        if self.bucket_size() != other.bucket_size() {
+            return Err(format!(
+                "Bucket size {} does not match the expected {}",
+                self.bucket_size(),
+                other.bucket_size()
+            ));
+        }
+        self.node_ttl = other.node_ttl;
+        self.subnets = other
+            .subnets
+            .as_ref()
+            .map(|s| Subnets::new(s.limits, s.network, &self.buckets));
+        Ok(())
+    }
+
+    /// Get liveness information about a node in the table.
+    pub fn node_info(&self, id: &TId) -> Option<&NodeInfo> {
+        let index = self.bucket_index(id)?;
+        self.buckets[index].node_info(id)
+    }
+
+    fn check_subnet_limits(
+        &self,
+        bucket: usize,
+        node: &Node<TId, TAddr>,
+    ) -> Result<(), UpdateError> {
+        match self.subnets {
+            Some(ref subnets) => {
+                let data = &self.buckets[bucket].data;
+                subnets.check(&subnets.networks(data), data, node)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Run `f` on the k-bucket with a predicate allowing replacements within
+    /// subnet limits to be promoted, then update the number of nodes per
+    /// network.
+    fn modify_bucket<F, R>(&mut self, index: usize, f: F) -> R
+    where
+        F: FnOnce(&mut KBucket<TId, TAddr>, AllowedFn<TId, TAddr>) -> R,
+    {
+        let bucket = &mut self.buckets[index];
+        let subnets = match self.subnets {
+            Some(ref mut subnets) => subnets,
+            None => return f(bucket, &|_, _| true),
+        };
+        let before = subnets.networks(&bucket.data);
+        let result = {
+            let subnets = &*subnets;
+            f(bucket, &|data, node| {
+                subnets.check(&before, data, node).is_ok()
+            })
+        };
+        let after = subnets.networks(&bucket.data);
+        subnets.replace(&before, &after);
+        result
+    }
+
+    fn bucket_size(&self) -> usize {
+        self.buckets.first().map_or(BUCKET_SIZE, |b| b.size)
+    }
 
     #[inline]
     fn distance(id1: &TId, id2: &TId) -> TId {
         id1.bitxor(id2)
     }
 
+    /// Bucket for the ID, `None` for our ID or IDs out of the hash size.
+    fn bucket_index(&self, id: &TId) -> Option<usize> {
+        let bits = KNodeTable::<TId, TAddr>::distance(&self.this_id, id).bits();
+        if bits > 0 && bits <= self.hash_size {
+            Some(bits - 1)
+        } else {
+            None
+        }
+    }
+
     fn bucket_number(&self, id: &TId) -> usize {
         let diff = KNodeTable::<TId, TAddr>::distance(&self.this_id, id);
         debug_assert!(!diff.is_zero());
         let res = diff.bits() - 1;
         if res >= self.hash_size {
-            panic!(format!(
+            panic!(
                 "Distance between IDs {:?} and {:?} is {:?}, which is \
                  greater than the hash size ({:?})",
                 id, self.this_id, res, self.hash_size
-            ));
+            );
         }
         debug!(
             "ID {:?} relative to own ID {:?} falls into bucket {:?}",
@@ -108,37 +317,287 @@ where
         TId::gen(self.hash_size)
     }
 
+    fn hash_size(&self) -> usize {
+        self.hash_size
+    }
+
+    fn bucket_size(&self) -> usize {
+        KNodeTable::bucket_size(self)
+    }
+
     fn update(&mut self, node: &Node<TId, TAddr>) -> bool {
+        self.update_at(node, Instant::now())
+    }
+
+    fn update_at(&mut self, node: &Node<TId, TAddr>, now: Instant) -> bool {
+        self.try_update_at(node, now).is_ok()
+    }
+
+    /// Nodes exceeding subnet limits are neither cached as replacements nor
+    /// promoted from the cache later.
+    fn try_update_at(&mut self, node: &Node<TId, TAddr>, now: Instant) -> Result<(), UpdateError> {
         assert!(node.id != self.this_id);
         let bucket = self.bucket_number(&node.id);
-        self.buckets[bucket].update(node)
+        self.check_subnet_limits(bucket, node)?;
+        if self.modify_bucket(bucket, |b, _| b.update_at(node, now)) {
+            Ok(())
+        } else {
+            Err(UpdateError::BucketFull)
+        }
     }
 
     fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
         debug_assert!(count > 0);
-        assert!(*id != self.this_id);
 
-        let mut data_copy: Vec<_> = self
-            .buckets
-            .iter()
-            .flat_map(|b| &b.data)
-            .map(|n| n.clone())
-            .collect();
+        let mut data_copy: Vec<_> = self.buckets.iter().flat_map(|b| &b.data).cloned().collect();
         data_copy.sort_by_key(|n| KNodeTable::<TId, TAddr>::distance(id, &n.id));
         data_copy[0..cmp::min(count, data_copy.len())].to_vec()
     }
 
-    fn pop_oldest(&mut self) -> Vec<Node<TId, TAddr>> {
-        // For every full k-bucket, pop the last.
-        // TODO(divius): TTL expiration?
+    fn pop_oldest(&mut self, now: Instant) -> Vec<Node<TId, TAddr>> {
+        let mut result = self.pop_expired(now);
+        // For every k-bucket that rejected a newcomer or has a failing node, pop
+        // the worst, unless a node from it is being checked already.
+        for index in 0..self.buckets.len() {
+            if !self.buckets[index].needs_check() {
+                continue;
+            }
+            if let Some(oldest) = self.modify_bucket(index, |b, allowed| b.pop_oldest(now, allowed))
+            {
+                result.push(oldest);
+            }
+        }
+        result
+    }
+
+    fn record_response(&mut self, id: &TId, rtt: Duration, now: Instant) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].record_response(id, rtt, now);
+        }
+    }
+
+    fn record_failure(&mut self, id: &TId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].record_failure(id);
+        }
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>> {
+        let index = self.bucket_index(id)?;
+        self.modify_bucket(index, |b, allowed| b.remove(id, Instant::now(), allowed))
+    }
+
+    fn get(&self, id: &TId) -> Option<&Node<TId, TAddr>> {
+        let index = self.bucket_index(id)?;
+        self.buckets[index].data.iter().find(|n| n.id == *id)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Node<TId, TAddr>> + 'a> {
+        Box::new(self.buckets.iter().flat_map(|b| b.data.iter()))
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.data.len()).sum()
+    }
+
+    fn record_lookup(&mut self, id: &TId, now: Instant) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].record_lookup(now);
+        }
+    }
+
+    fn stale_buckets<R: Rng>(&self, now: Instant, interval: Duration, rng: &mut R) -> Vec<TId> {
         self.buckets
-            .iter_mut()
-            .filter(|b| !b.data.is_empty() && b.size == b.data.len())
-            .map(|b| b.data.pop_front().unwrap())
+            .iter()
+            .enumerate()
+            .filter(|&(_, b)| b.last_lookup + interval <= now)
+            .map(|(index, _)| self.random_id_in_bucket(index, rng))
             .collect()
     }
 }
 
+impl<TId> KNodeTable<TId, net::SocketAddr>
+where
+    TId: GenericId,
+{
+    /// Limit the number of nodes from the same network.
+    ///
+    /// No limits are enforced by default. Nodes already in the table are
+    /// not affected.
+    pub fn set_subnet_limits(&mut self, limits: SubnetLimits) {
+        self.subnets = Some(Subnets::new(limits, subnet, &self.buckets));
+    }
+}
+
+impl<TAddr> Subnets<TAddr> {
+    fn new<TId>(
+        limits: SubnetLimits,
+        network: NetworkFn<TAddr>,
+        buckets: &[KBucket<TId, TAddr>],
+    ) -> Subnets<TAddr> {
+        let mut subnets = Subnets {
+            limits,
+            network,
+            counts: HashMap::new(),
+        };
+        for bucket in buckets {
+            let networks = subnets.networks(&bucket.data);
+            subnets.replace(&HashMap::new(), &networks);
+        }
+        subnets
+    }
+
+    /// Number of nodes per network among `nodes`.
+    fn networks<TId>(&self, nodes: &VecDeque<Node<TId, TAddr>>) -> HashMap<net::IpAddr, usize> {
+        let mut result = HashMap::new();
+        for node in nodes {
+            *result.entry((self.network)(&node.address)).or_insert(0) += 1;
+        }
+        result
+    }
+
+    /// Replace nodes of a k-bucket counted as `before` with ones counted as
+    /// `after`.
+    fn replace(
+        &mut self,
+        before: &HashMap<net::IpAddr, usize>,
+        after: &HashMap<net::IpAddr, usize>,
+    ) {
+        for (net, count) in before {
+            let total = self.counts.get_mut(net).unwrap();
+            *total -= count;
+            if *total == 0 {
+                let _ = self.counts.remove(net);
+            }
+        }
+        for (net, count) in after {
+            *self.counts.entry(*net).or_insert(0) += count;
+        }
+    }
+
+    /// Check whether `node` fits into a k-bucket with nodes `data`, which
+    /// had nodes counted as `before` when the counts were last updated.
+    fn check<TId: GenericId>(
+        &self,
+        before: &HashMap<net::IpAddr, usize>,
+        data: &VecDeque<Node<TId, TAddr>>,
+        node: &Node<TId, TAddr>,
+    ) -> Result<(), UpdateError>
+    where
+        TAddr: Debug,
+    {
+        let net = (self.network)(&node.address);
+        // The node itself is not counted, so that it can be updated
+        let in_bucket = data
+            .iter()
+            .filter(|x| x.id != node.id && (self.network)(&x.address) == net)
+            .count();
+        if let Some(limit) = self.limits.per_bucket {
+            if in_bucket >= limit {
+                debug!(
+                    "Too many nodes from {} in kbucket, rejecting {:?}",
+                    net, node
+                );
+                return Err(UpdateError::BucketSubnetLimit);
+            }
+        }
+        if let Some(limit) = self.limits.per_table {
+            let counted = before.get(&net).map_or(0, |x| *x);
+            let in_table = self.counts.get(&net).map_or(0, |x| *x) - counted + in_bucket;
+            if in_table >= limit {
+                debug!("Too many nodes from {} in table, rejecting {:?}", net, node);
+                return Err(UpdateError::TableSubnetLimit);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot of the table: own ID, sizes and nodes of every k-bucket.
+///
+/// Liveness information and replacement caches are not saved.
+impl<TId> serialize::Encodable for KNodeTable<TId, net::SocketAddr>
+where
+    TId: GenericId,
+{
+    fn encode<S: serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let bucket_size = self.bucket_size();
+        s.emit_struct("KNodeTable", 5, |s| {
+            s.emit_struct_field("version", 0, |s2| s2.emit_u32(SNAPSHOT_VERSION))?;
+            s.emit_struct_field("this_id", 1, |s2| self.this_id.encode(s2))?;
+            s.emit_struct_field("hash_size", 2, |s2| s2.emit_usize(self.hash_size))?;
+            s.emit_struct_field("bucket_size", 3, |s2| s2.emit_usize(bucket_size))?;
+            s.emit_struct_field("buckets", 4, |s2| {
+                s2.emit_seq(self.buckets.len(), |s3| {
+                    for (i, bucket) in self.buckets.iter().enumerate() {
+                        s3.emit_seq_elt(i, |s4| {
+                            let nodes: Vec<_> = bucket.data.iter().cloned().collect();
+                            serialize::Encodable::encode(&nodes, s4)
+                        })?;
+                    }
+                    Ok(())
+                })
+            })
+        })
+    }
+}
+
+impl<TId> serialize::Decodable for KNodeTable<TId, net::SocketAddr>
+where
+    TId: GenericId,
+{
+    fn decode<D: serialize::Decoder>(
+        d: &mut D,
+    ) -> Result<KNodeTable<TId, net::SocketAddr>, D::Error> {
+        d.read_struct("KNodeTable", 5, |d| {
+            let version = d.read_struct_field("version", 0, |d2| d2.read_u32())?;
+            if version != SNAPSHOT_VERSION {
+                let err = format!("Unsupported node table version {}", version);
+                return Err(d.error(&err));
+            }
+            let this_id = d.read_struct_field("this_id", 1, TId::decode)?;
+            let hash_size = d.read_struct_field("hash_size", 2, |d2| d2.read_usize())?;
+            let bucket_size = d.read_struct_field("bucket_size", 3, |d2| d2.read_usize())?;
+            if bucket_size == 0 {
+                return Err(d.error("Bucket size must be positive"));
+            }
+            let buckets: Vec<Vec<Node<TId, net::SocketAddr>>> =
+                d.read_struct_field("buckets", 4, serialize::Decodable::decode)?;
+            if buckets.len() != hash_size {
+                let err = format!("Expected {} buckets, got {}", hash_size, buckets.len());
+                return Err(d.error(&err));
+            }
+
+            let mut table = KNodeTable::new_with_details(this_id, bucket_size, hash_size);
+            let now = Instant::now();
+            for (i, nodes) in buckets.into_iter().enumerate() {
+                for node in nodes {
+                    let bits =
+                        KNodeTable::<TId, net::SocketAddr>::distance(&table.this_id, &node.id)
+                            .bits();
+                    if bits != i + 1 {
+                        let err = format!("Node {:?} does not belong to bucket {}", node.id, i);
+                        return Err(d.error(&err));
+                    }
+                    table.buckets[i].update_at(&node, now);
+                }
+            }
+            Ok(table)
+        })
+    }
+}
+
+impl NodeInfo {
+    fn new(now: Instant) -> NodeInfo {
+        NodeInfo {
+            last_seen: now,
+            last_response: None,
+            failures: 0,
+            rtt: None,
+        }
+    }
+}
+
 impl<TId, TAddr> KBucket<TId, TAddr>
 where
     TId: GenericId,
@@ -149,26 +608,72 @@ where
         KBucket {
             data: VecDeque::new(),
             size: k,
+            last_lookup: Instant::now(),
+            info: HashMap::new(),
+            replacements: VecDeque::new(),
+            checked: None,
+            rejected: false,
         }
     }
 
     pub fn update(&mut self, node: &Node<TId, TAddr>) -> bool {
+        self.update_at(node, Instant::now())
+    }
+
+    /// Update a node seen at the given time.
+    pub fn update_at(&mut self, node: &Node<TId, TAddr>, now: Instant) -> bool {
+        if let Some((popped, promoted)) = self.checked.take() {
+            if popped == node.id {
+                if let Some(promoted) = promoted {
+                    self.demote(&promoted);
+                }
+            } else {
+                self.checked = Some((popped, promoted));
+            }
+        }
+
         if self.data.iter().any(|x| x.id == node.id) {
             self.update_position(node.clone());
+            self.touch(&node.id, now);
             debug!("Promoted node {:?} to the top of kbucket", node);
             true
         } else if self.data.len() == self.size {
-            debug!("Not adding new node {:?} to kbucket - no space left", node);
+            debug!(
+                "No space left in kbucket, caching node {:?} as replacement",
+                node
+            );
+            self.replacements.retain(|x| x.id != node.id);
+            if self.replacements.len() == self.size {
+                let _ = self.replacements.pop_front();
+            }
+            self.replacements.push_back(node.clone());
+            self.rejected = true;
             false
         } else {
             self.data.push_back(node.clone());
+            self.touch(&node.id, now);
             debug!("Added new node {:?} to kbucket", node);
             true
         }
     }
 
+    /// Pop the node failing most, or the oldest one if none is failing.
+    ///
+    /// The newest replacement for which `allowed` returns true takes place
+    /// of the popped node as seen at time `now`. The popped node is being
+    /// checked until it is updated or its failure is recorded.
+    pub fn pop_oldest<F>(&mut self, now: Instant, allowed: F) -> Option<Node<TId, TAddr>>
+    where
+        F: Fn(&VecDeque<Node<TId, TAddr>>, &Node<TId, TAddr>) -> bool,
+    {
+        let oldest = self.pop_worst()?;
+        let promoted = self.promote_replacement(now, allowed);
+        self.start_check(&oldest.id, promoted);
+        Some(oldest)
+    }
+
     pub fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
-        let mut data_copy: Vec<_> = self.data.iter().map(|n| n.clone()).collect();
+        let mut data_copy: Vec<_> = self.data.iter().cloned().collect();
         data_copy.sort_by_key(|n| KNodeTable::<TId, TAddr>::distance(id, &n.id));
         data_copy[0..cmp::min(count, data_copy.len())].to_vec()
     }
@@ -179,16 +684,224 @@ where
     pub fn size(&self) -> usize {
         self.size
     }
+    pub fn replacements(&self) -> &VecDeque<Node<TId, TAddr>> {
+        &self.replacements
+    }
+    /// Get liveness information about a node in the k-bucket.
+    pub fn node_info(&self, id: &TId) -> Option<&NodeInfo> {
+        self.info.get(id)
+    }
+
+    /// Remove a node, replacing it with the newest replacement for which
+    /// `allowed` returns true, as seen at time `now`.
+    pub fn remove<F>(&mut self, id: &TId, now: Instant, allowed: F) -> Option<Node<TId, TAddr>>
+    where
+        F: Fn(&VecDeque<Node<TId, TAddr>>, &Node<TId, TAddr>) -> bool,
+    {
+        let node = self.take(id)?;
+        let _ = self.promote_replacement(now, allowed);
+        Some(node)
+    }
+
+    /// Pop nodes not heard from within `ttl` before `now`.
+    ///
+    /// The newest replacements for which `allowed` returns true take place
+    /// of the popped nodes as seen at time `now`.
+    pub fn pop_expired<F>(
+        &mut self,
+        now: Instant,
+        ttl: Duration,
+        allowed: F,
+    ) -> Vec<Node<TId, TAddr>>
+    where
+        F: Fn(&VecDeque<Node<TId, TAddr>>, &Node<TId, TAddr>) -> bool,
+    {
+        let info = &self.info;
+        let (expired, alive): (Vec<_>, Vec<_>) = self.data.drain(..).partition(|node| {
+            info.get(&node.id).is_some_and(|info| {
+                let heard = info
+                    .last_response
+                    .map_or(info.last_seen, |t| cmp::max(t, info.last_seen));
+                heard + ttl <= now
+            })
+        });
+        self.data = alive.into();
+        for node in &expired {
+            debug!("Node {:?} expired", node);
+            let _ = self.info.remove(&node.id);
+        }
+        while self.promote_replacement(now, &allowed).is_some() {}
+        expired
+    }
+
+    /// Record a successful response with the given round-trip time.
+    pub fn record_response(&mut self, id: &TId, rtt: Duration, now: Instant) {
+        if let Some(info) = self.info.get_mut(id) {
+            info.last_response = Some(now);
+            info.failures = 0;
+            // Same smoothing as for TCP (RFC 6298)
+            info.rtt = Some(match info.rtt {
+                Some(old) => old * 7 / 8 + rtt / 8,
+                None => rtt,
+            });
+        }
+    }
+
+    /// Record a request the node failed to answer.
+    ///
+    /// For the popped node being checked, this finishes the check.
+    pub fn record_failure(&mut self, id: &TId) {
+        if let Some(info) = self.info.get_mut(id) {
+            info.failures += 1;
+        }
+        if self
+            .checked
+            .as_ref()
+            .is_some_and(|(popped, _)| popped == id)
+        {
+            debug!("Node {:?} failed the check, keeping its replacement", id);
+            self.checked = None;
+        }
+    }
+
+    /// Whether the worst node should be popped for a check: a newcomer
+    /// rejected by the still full k-bucket waits in the replacement cache or
+    /// a node keeps failing, and no node is being checked yet.
+    pub(crate) fn needs_check(&self) -> bool {
+        let waiting =
+            self.rejected && self.data.len() >= self.size && !self.replacements.is_empty();
+        self.checked.is_none() && (waiting || self.max_failures() >= MAX_FAILURES)
+    }
+
+    /// Last time a lookup fell into this k-bucket.
+    pub(crate) fn last_lookup(&self) -> Instant {
+        self.last_lookup
+    }
+    pub(crate) fn record_lookup(&mut self, now: Instant) {
+        self.last_lookup = cmp::max(self.last_lookup, now);
+    }
+
+    /// Split the k-bucket into ones for IDs with `predicate` false and true.
+    ///
+    /// The order of nodes and their liveness information is preserved.
+    pub(crate) fn split<F>(self, predicate: F) -> (KBucket<TId, TAddr>, KBucket<TId, TAddr>)
+    where
+        F: Fn(&TId) -> bool,
+    {
+        let mut result = (KBucket::new(self.size), KBucket::new(self.size));
+        result.0.last_lookup = self.last_lookup;
+        result.1.last_lookup = self.last_lookup;
+        let mut info = self.info;
+        for node in self.data {
+            let bucket = if predicate(&node.id) {
+                &mut result.1
+            } else {
+                &mut result.0
+            };
+            if let Some(node_info) = info.remove(&node.id) {
+                let _ = bucket.info.insert(node.id.clone(), node_info);
+            }
+            bucket.data.push_back(node);
+        }
+        for node in self.replacements {
+            if predicate(&node.id) {
+                result.1.replacements.push_back(node);
+            } else {
+                result.0.replacements.push_back(node);
+            }
+        }
+        result
+    }
+
+    // Remove a node without promoting a replacement
+    fn take(&mut self, id: &TId) -> Option<Node<TId, TAddr>> {
+        let pos = match self.data.iter().position(|x| x.id == *id) {
+            Some(pos) => pos,
+            None => {
+                // Forget about a replacement as well
+                let pos = self.replacements.iter().position(|x| x.id == *id)?;
+                let _ = self.replacements.remove(pos);
+                return None;
+            }
+        };
+        let node = self.data.remove(pos)?;
+        let _ = self.info.remove(id);
+        debug!("Removed node {:?} from kbucket", node);
+        Some(node)
+    }
+
+    // Remove the node failing most, or the oldest one if none is failing
+    fn pop_worst(&mut self) -> Option<Node<TId, TAddr>> {
+        let mut index = 0;
+        for (i, node) in self.data.iter().enumerate() {
+            if self.failures(&node.id) > self.failures(&self.data[index].id) {
+                index = i;
+            }
+        }
+        let oldest = self.data.remove(index)?;
+        let _ = self.info.remove(&oldest.id);
+        Some(oldest)
+    }
+
+    // Move the newest replacement for which `allowed` returns true to the
+    // k-bucket as seen at `now`, if there is space
+    fn promote_replacement<F>(&mut self, now: Instant, allowed: F) -> Option<TId>
+    where
+        F: Fn(&VecDeque<Node<TId, TAddr>>, &Node<TId, TAddr>) -> bool,
+    {
+        if self.data.len() >= self.size {
+            return None;
+        }
+        let data = &self.data;
+        let pos = self
+            .replacements
+            .iter()
+            .rposition(|node| allowed(data, node))?;
+        let replacement = self.replacements.remove(pos).unwrap();
+        debug!("Promoting replacement {:?} in kbucket", replacement);
+        let id = replacement.id.clone();
+        self.data.push_back(replacement);
+        self.touch(&id, now);
+        Some(id)
+    }
+
+    fn start_check(&mut self, popped: &TId, promoted: Option<TId>) {
+        self.checked = Some((popped.clone(), promoted));
+        self.rejected = false;
+    }
+
+    fn touch(&mut self, id: &TId, now: Instant) {
+        self.info
+            .entry(id.clone())
+            .or_insert_with(|| NodeInfo::new(now))
+            .last_seen = now;
+    }
+
+    fn failures(&self, id: &TId) -> u32 {
+        self.info.get(id).map_or(0, |info| info.failures)
+    }
+
+    fn max_failures(&self) -> u32 {
+        self.data
+            .iter()
+            .map(|node| self.failures(&node.id))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn demote(&mut self, id: &TId) {
+        if let Some(pos) = self.data.iter().position(|x| x.id == *id) {
+            let node = self.data.remove(pos).unwrap();
+            let _ = self.info.remove(id);
+            debug!("Moving node {:?} back to replacements", node);
+            self.replacements.push_back(node);
+        }
+    }
 
     fn update_position(&mut self, node: Node<TId, TAddr>) {
         // TODO(divius): 1. optimize, 2. make it less ugly
         let mut new_data = VecDeque::with_capacity(self.data.len());
-        new_data.extend(
-            self.data
-                .iter()
-                .filter(|x| x.id != node.id)
-                .map(|x| x.clone()),
-        );
+        new_data.extend(self.data.iter().filter(|x| x.id != node.id).cloned());
         new_data.push_back(node.clone());
         self.data = new_data;
     }
@@ -196,7 +909,13 @@ where
 
 #[cfg(test)]
 mod test {
+    use rand;
+    use rand::prng::XorShiftRng;
+    use rand::SeedableRng;
+    use rustc_serialize::json;
+    use std::collections::{HashMap, VecDeque};
     use std::net;
+    use std::time::{Duration, Instant};
 
     use super::super::GenericNodeTable;
     use super::super::Node;
@@ -204,6 +923,7 @@ mod test {
     use super::KBucket;
     use super::KNodeTable;
     use super::DEFAULT_HASH_SIZE;
+    use super::{SubnetLimits, UpdateError};
 
     use super::super::utils::test;
     type TestsIdType = test::IdType;
@@ -215,12 +935,17 @@ mod test {
                 .map(|i| test::new_node(test::make_id(i)))
                 .collect(),
             size: 3,
+            last_lookup: Instant::now(),
+            info: HashMap::new(),
+            replacements: VecDeque::new(),
+            checked: None,
+            rejected: false,
         }
     }
 
     fn assert_node_list_eq(
         expected: &[&Node<TestsIdType, net::SocketAddr>],
-        actual: &Vec<Node<TestsIdType, net::SocketAddr>>,
+        actual: &[Node<TestsIdType, net::SocketAddr>],
     ) {
         let act: Vec<TestsIdType> = actual.iter().map(|n| n.id.clone()).collect();
         let exp: Vec<TestsIdType> = expected.iter().map(|n| n.id.clone()).collect();
@@ -230,6 +955,8 @@ mod test {
     #[test]
     fn test_nodetable_new() {
         let n = KNodeTable::<u64, ()>::new(42);
+        assert_eq!(64, n.buckets.len());
+        let n = KNodeTable::<TestsIdType, ()>::new(test::make_id(42));
         assert_eq!(DEFAULT_HASH_SIZE, n.buckets.len());
     }
 
@@ -260,15 +987,38 @@ mod test {
             lengths
         );
 
-        let nodes = n.pop_oldest();
+        // Full k-buckets are not checked until they reject a newcomer
+        assert!(n.pop_oldest(Instant::now()).is_empty());
+
+        n.update(&test::new_node(test::make_id(46)));
+        n.update(&test::new_node(test::make_id(47)));
+        assert!(!n.update(&test::new_node(test::make_id(44))));
+        let nodes = n.pop_oldest(Instant::now());
         assert_eq!(1, nodes.len());
-        assert_eq!(test::make_id(41), nodes[0].id);
-        lengths[1] = 1;
+        assert_eq!(test::make_id(46), nodes[0].id);
+        lengths[2] = 2;
         assert_eq!(
             n.buckets().iter().map(|b| b.data.len()).collect::<Vec<_>>(),
             lengths
         );
-        assert_eq!(test::make_id(40), n.buckets[1].data[0].id);
+        assert_eq!(test::make_id(44), n.buckets[2].data[1].id);
+
+        // Only one node per k-bucket is checked at a time
+        assert!(!n.update(&test::new_node(test::make_id(45))));
+        assert!(n.pop_oldest(Instant::now()).is_empty());
+        // The check is over when the node answers
+        assert!(n.update(&nodes[0]));
+        assert_eq!(test::make_id(46), n.buckets[2].data[1].id);
+        let nodes = n.pop_oldest(Instant::now());
+        assert_eq!(1, nodes.len());
+        assert_eq!(test::make_id(47), nodes[0].id);
+        // ... or fails to
+        assert!(n.pop_oldest(Instant::now()).is_empty());
+        n.record_failure(&nodes[0].id);
+        assert!(n.get(&nodes[0].id).is_none());
+        assert!(n.pop_oldest(Instant::now()).is_empty());
+        assert!(!n.update(&test::new_node(test::make_id(47))));
+        assert_eq!(1, n.pop_oldest(Instant::now()).len());
     }
 
     #[test]
@@ -277,6 +1027,8 @@ mod test {
             buckets: vec![prepare(1), prepare(3), prepare(1)],
             this_id: test::make_id(0),
             hash_size: DEFAULT_HASH_SIZE,
+            node_ttl: None,
+            subnets: None,
         };
         // 0 xor 3 = 3, 1 xor 3 = 2, 2 xor 3 = 1
         let id = test::make_id(3);
@@ -307,8 +1059,16 @@ mod test {
         assert!(n.update(&node1));
         assert!(n.update(&node2));
         assert!(n.update(&node3));
-        assert_node_list_eq(&vec![&node3], &n.find(&test::make_id(0b1111), 1));
-        assert_node_list_eq(&vec![&node2], &n.find(&test::make_id(0b1011), 1));
+        assert_node_list_eq(&[&node3], &n.find(&test::make_id(0b1111), 1));
+        assert_node_list_eq(&[&node2], &n.find(&test::make_id(0b1011), 1));
+    }
+
+    #[test]
+    fn test_nodetable_find_own_id() {
+        let mut n = KNodeTable::new(test::make_id(0b0000));
+        let node = test::new_node(test::make_id(0b0101));
+        assert!(n.update(&node));
+        assert_node_list_eq(&[&node], &n.find(&test::make_id(0b0000), 1));
     }
 
     #[test]
@@ -323,9 +1083,9 @@ mod test {
 
     #[test]
     fn test_nodetable_random_id() {
-        let n = KNodeTable::<u64, ()>::new_with_details(42, 1, DEFAULT_HASH_SIZE);
+        let n = KNodeTable::<u64, ()>::new_with_details(42, 1, 64);
         for _ in 0..100 {
-            assert!(n.random_id().bits() <= DEFAULT_HASH_SIZE);
+            assert!(n.random_id().bits() <= 64);
         }
         assert!(n.random_id() != n.random_id());
     }
@@ -359,7 +1119,60 @@ mod test {
     fn test_kbucket_update_conflict() {
         let mut b = prepare(3); // 3 is size
         let node = test::new_node(test::make_id(42));
-        assert!(!b.update(&node))
+        assert!(!b.update(&node));
+        assert_eq!(3, b.data.len());
+        assert_eq!(node.id, b.replacements[0].id);
+    }
+
+    #[test]
+    fn test_kbucket_replacements_bounded() {
+        let mut b = prepare(3);
+        for i in 10..15 {
+            assert!(!b.update(&test::new_node(test::make_id(i))));
+        }
+        // Seen again, moves to the end
+        assert!(!b.update(&test::new_node(test::make_id(12))));
+        let ids: Vec<_> = b.replacements().iter().map(|n| n.id.clone()).collect();
+        assert_eq!(
+            vec![test::make_id(13), test::make_id(14), test::make_id(12)],
+            ids
+        );
+    }
+
+    #[test]
+    fn test_kbucket_pop_oldest_promotes_replacement() {
+        let mut b = prepare(3);
+        b.update(&test::new_node(test::make_id(10)));
+        b.update(&test::new_node(test::make_id(11)));
+
+        let oldest = b.pop_oldest(Instant::now(), |_, _| true).unwrap();
+        assert_eq!(test::make_id(0), oldest.id);
+        // The newest replacement takes its place
+        assert_eq!(3, b.data.len());
+        assert_eq!(test::make_id(11), b.data[2].id);
+        assert_eq!(1, b.replacements.len());
+        assert_eq!(test::make_id(10), b.replacements[0].id);
+
+        // The oldest node is still alive: it is back, the replacement is not
+        assert!(b.update(&oldest));
+        assert_eq!(3, b.data.len());
+        assert_eq!(test::make_id(0), b.data[2].id);
+        assert!(b.data.iter().all(|n| n.id != test::make_id(11)));
+        assert_eq!(test::make_id(11), b.replacements[1].id);
+    }
+
+    #[test]
+    fn test_kbucket_pop_oldest_dead_node() {
+        let mut b = prepare(3);
+        b.update(&test::new_node(test::make_id(10)));
+        assert_eq!(
+            test::make_id(0),
+            b.pop_oldest(Instant::now(), |_, _| true).unwrap().id
+        );
+        // Unrelated updates do not undo the replacement
+        assert!(b.update(&test::new_node(test::make_id(1))));
+        assert_eq!(test::make_id(10), b.data[1].id);
+        assert!(b.replacements.is_empty());
     }
 
     #[test]
@@ -380,4 +1193,396 @@ mod test {
         // 0 xor 2 = 2, 1 xor 2 = 3, 2 xor 2 = 0
         assert_node_list_eq(&[&b.data[2], &b.data[0], &b.data[1]], &b.find(&id, 100));
     }
+
+    #[test]
+    fn test_kbucket_node_info() {
+        let mut b = KBucket::new(3);
+        let node = test::new_node(test::make_id(1));
+        assert!(b.node_info(&node.id).is_none());
+        b.update(&node);
+        let last_seen = b.node_info(&node.id).unwrap().last_seen;
+        assert!(b.node_info(&node.id).unwrap().last_response.is_none());
+
+        b.record_failure(&node.id);
+        b.record_failure(&node.id);
+        assert_eq!(2, b.node_info(&node.id).unwrap().failures);
+
+        b.record_response(&node.id, Duration::from_millis(80), Instant::now());
+        b.record_response(&node.id, Duration::from_millis(160), Instant::now());
+        let info = b.node_info(&node.id).unwrap();
+        assert_eq!(0, info.failures);
+        assert!(info.last_response.unwrap() >= last_seen);
+        assert_eq!(Some(Duration::from_millis(90)), info.rtt);
+
+        // Unknown nodes are ignored
+        b.record_failure(&test::make_id(2));
+        assert!(b.node_info(&test::make_id(2)).is_none());
+    }
+
+    #[test]
+    fn test_kbucket_pop_oldest_failing() {
+        let mut b = KBucket::new(3);
+        for i in 0..3 {
+            b.update(&test::new_node(test::make_id(i)));
+        }
+        b.record_failure(&test::make_id(1));
+        assert_eq!(
+            test::make_id(1),
+            b.pop_oldest(Instant::now(), |_, _| true).unwrap().id
+        );
+        assert!(b.node_info(&test::make_id(1)).is_none());
+        assert_eq!(
+            test::make_id(0),
+            b.pop_oldest(Instant::now(), |_, _| true).unwrap().id
+        );
+    }
+
+    #[test]
+    fn test_nodetable_pop_oldest_failing() {
+        let mut n = KNodeTable::new(test::make_id(0));
+        let node = test::new_node(test::make_id(1));
+        n.update(&node);
+        n.update(&test::new_node(test::make_id(2)));
+        for _ in 0..2 {
+            n.record_failure(&node.id);
+            assert!(n.pop_oldest(Instant::now()).is_empty());
+        }
+        n.record_failure(&node.id);
+        assert_eq!(3, n.node_info(&node.id).unwrap().failures);
+        let popped = n.pop_oldest(Instant::now());
+        assert_eq!(1, popped.len());
+        assert_eq!(node.id, popped[0].id);
+        assert!(n.node_info(&node.id).is_none());
+    }
+
+    #[test]
+    fn test_nodetable_record_out_of_table() {
+        let mut n: KNodeTable<TestsIdType, net::SocketAddr> =
+            KNodeTable::new_with_details(test::make_id(0), 2, 8);
+        // IDs too long for the hash size are ignored
+        let id = vec![1, 2];
+        n.record_response(&id, Duration::from_millis(80), Instant::now());
+        n.record_failure(&id);
+        n.record_failure(&test::make_id(0));
+        assert!(n.is_empty());
+    }
+
+    #[test]
+    fn test_nodetable_pop_expired() {
+        let start = Instant::now();
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+        let mut n = KNodeTable::new(test::make_id(0));
+        n.set_node_ttl(Some(Duration::from_secs(10 * 60)));
+        n.update_at(&test::new_node(test::make_id(1)), start);
+        n.update_at(&test::new_node(test::make_id(2)), minutes(5));
+        n.update_at(&test::new_node(test::make_id(4)), start);
+        // Seen again later
+        n.update_at(&test::new_node(test::make_id(4)), minutes(8));
+
+        assert!(n.pop_expired(minutes(9)).is_empty());
+        let expired = n.pop_expired(minutes(12));
+        assert_eq!(1, expired.len());
+        assert_eq!(test::make_id(1), expired[0].id);
+        assert!(n.node_info(&test::make_id(1)).is_none());
+
+        let expired: Vec<_> = n
+            .pop_expired(minutes(20))
+            .into_iter()
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(vec![test::make_id(2), test::make_id(4)], expired);
+        assert!(n.find(&test::make_id(0), 10).is_empty());
+    }
+
+    #[test]
+    fn test_nodetable_pop_expired_replacements() {
+        let start = Instant::now();
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+        let mut n = KNodeTable::new_with_details(test::make_id(0), 2, 8);
+        n.set_node_ttl(Some(Duration::from_secs(10 * 60)));
+        n.update_at(&node_at(4, "10.0.1.1:8000"), start);
+        n.update_at(&node_at(5, "10.0.2.1:8000"), minutes(8));
+        assert!(!n.update_at(&node_at(6, "10.0.3.1:8000"), minutes(8)));
+        // The replacement takes the free slot, nothing to check
+        let popped = n.pop_oldest(minutes(12));
+        assert_eq!(1, popped.len());
+        assert_eq!(test::make_id(4), popped[0].id);
+        assert!(n.contains(&test::make_id(5)));
+        assert!(n.contains(&test::make_id(6)));
+        assert!(n.pop_oldest(minutes(12)).is_empty());
+
+        let mut n = KNodeTable::new_with_details(test::make_id(0), 2, 8);
+        n.set_node_ttl(Some(Duration::from_secs(10 * 60)));
+        n.set_subnet_limits(SubnetLimits {
+            per_bucket: None,
+            per_table: Some(1),
+        });
+        n.update_at(&node_at(4, "10.0.1.1:8000"), start);
+        n.update_at(&node_at(5, "10.0.2.1:8000"), minutes(8));
+        assert!(!n.update_at(&node_at(6, "10.0.3.1:8000"), minutes(8)));
+        n.update_at(&node_at(1, "10.0.3.2:8000"), minutes(8));
+        // The replacement is over the limit, but there is space now
+        let popped = n.pop_oldest(minutes(12));
+        assert_eq!(1, popped.len());
+        assert_eq!(test::make_id(4), popped[0].id);
+        assert!(n.contains(&test::make_id(5)));
+        assert!(!n.contains(&test::make_id(6)));
+    }
+
+    #[test]
+    fn test_nodetable_pop_expired_disabled() {
+        let start = Instant::now();
+        let mut n = KNodeTable::new(test::make_id(0));
+        n.set_node_ttl(None);
+        n.update_at(&test::new_node(test::make_id(1)), start);
+        assert!(n
+            .pop_expired(start + Duration::from_secs(24 * 3600))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_nodetable_snapshot() {
+        let mut n = KNodeTable::new_with_details(test::make_id(0), 2, 8);
+        for i in &[5, 1, 7, 6] {
+            n.update(&test::new_node_with_port(
+                test::make_id(*i),
+                8000 + *i as u16,
+            ));
+        }
+        let encoded = json::encode(&n).unwrap();
+        let decoded: KNodeTable<TestsIdType, net::SocketAddr> = json::decode(&encoded).unwrap();
+        assert_eq!(n.this_id, decoded.this_id);
+        assert_eq!(8, decoded.hash_size);
+        assert_eq!(8, decoded.buckets.len());
+        for (b1, b2) in n.buckets.iter().zip(&decoded.buckets) {
+            assert_eq!(2, b2.size);
+            assert_node_list_eq(
+                &b1.data.iter().collect::<Vec<_>>(),
+                &Vec::from(b2.data.clone()),
+            );
+            for (n1, n2) in b1.data.iter().zip(&b2.data) {
+                assert_eq!(n1.address, n2.address);
+            }
+        }
+        assert!(decoded.node_info(&test::make_id(5)).is_some());
+    }
+
+    #[test]
+    fn test_nodetable_snapshot_invalid() {
+        let n =
+            KNodeTable::<TestsIdType, net::SocketAddr>::new_with_details(test::make_id(0), 2, 2);
+        let encoded = json::encode(&n).unwrap();
+        assert!(encoded.contains("\"version\":1"));
+
+        let wrong_version = encoded.replace("\"version\":1", "\"version\":42");
+        let result: Result<KNodeTable<TestsIdType, net::SocketAddr>, _> =
+            json::decode(&wrong_version);
+        assert!(result.is_err());
+
+        // Node 3 is in bucket 1, not 0
+        let wrong_bucket = encoded.replace(
+            "\"buckets\":[[]",
+            "\"buckets\":[[{\"address\":\"127.0.0.1:8008\",\"id\":\"03\"}]",
+        );
+        assert!(wrong_bucket != encoded);
+        let result: Result<KNodeTable<TestsIdType, net::SocketAddr>, _> =
+            json::decode(&wrong_bucket);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_nodetable_stale_buckets() {
+        let interval = Duration::from_secs(3600);
+        let mut n = KNodeTable::<TestsIdType, net::SocketAddr>::new_with_details(
+            test::make_id(0b1010_1010),
+            3,
+            8,
+        );
+        let now = Instant::now();
+        assert!(n
+            .stale_buckets(now, interval, &mut rand::thread_rng())
+            .is_empty());
+
+        let later = now + interval;
+        // 0b1010_1010 xor 0b1010_0000 = 0b1010, bucket 3
+        n.record_lookup(&test::make_id(0b1010_0000), later);
+        // Own ID does not fall into any bucket
+        n.record_lookup(&test::make_id(0b1010_1010), later);
+        let stale = n.stale_buckets(later, interval, &mut rand::thread_rng());
+        assert_eq!(7, stale.len());
+        let buckets: Vec<_> = stale
+            .iter()
+            .map(|id| id.bitxor(&n.this_id).bits() - 1)
+            .collect();
+        assert_eq!(vec![0, 1, 2, 4, 5, 6, 7], buckets);
+    }
+
+    #[test]
+    fn test_nodetable_random_id_in_bucket() {
+        let n = KNodeTable::<u64, ()>::new(0xdead_beef);
+        let mut rng = rand::thread_rng();
+        for index in 0..64 {
+            for _ in 0..10 {
+                let id = n.random_id_in_bucket(index, &mut rng);
+                assert_eq!(index, n.bucket_number(&id));
+            }
+        }
+        // Reproducible with a seeded generator
+        let id1 = n.random_id_in_bucket(40, &mut XorShiftRng::seed_from_u64(42));
+        let id2 = n.random_id_in_bucket(40, &mut XorShiftRng::seed_from_u64(42));
+        assert_eq!(id1, id2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_nodetable_random_id_in_bucket_overflow() {
+        let n = KNodeTable::<u64, ()>::new_with_details(42, 8, 8);
+        n.random_id_in_bucket(8, &mut rand::thread_rng());
+    }
+
+    #[test]
+    fn test_nodetable_get_remove() {
+        let mut n = KNodeTable::new_with_details(test::make_id(0), 2, 8);
+        assert!(n.is_empty());
+        for i in 1..8 {
+            n.update(&test::new_node_with_port(test::make_id(i), 8000 + i as u16));
+        }
+        // Nodes 6 and 7 did not fit into bucket 2 with 4 and 5
+        assert_eq!(5, n.len());
+        assert!(n.contains(&test::make_id(4)));
+        assert!(!n.contains(&test::make_id(6)));
+        assert!(!n.contains(&test::make_id(0)));
+        assert!(n.get(&test::make_id(200)).is_none());
+        assert_eq!(
+            test::make_addr(8003),
+            n.get(&test::make_id(3)).unwrap().address
+        );
+
+        let mut ids: Vec<_> = n.iter().map(|n| n.id.clone()).collect();
+        ids.sort();
+        let expected: Vec<_> = (1..6).map(test::make_id).collect();
+        assert_eq!(expected, ids);
+
+        assert_eq!(test::make_id(4), n.remove(&test::make_id(4)).unwrap().id);
+        assert!(n.remove(&test::make_id(4)).is_none());
+        assert!(n.remove(&test::make_id(0)).is_none());
+        // The newest replacement took its place
+        assert!(n.contains(&test::make_id(7)));
+        assert!(n.node_info(&test::make_id(7)).is_some());
+        assert_eq!(5, n.len());
+    }
+
+    fn node_at(id: u8, addr: &str) -> Node<TestsIdType, net::SocketAddr> {
+        Node {
+            id: test::make_id(id),
+            address: addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_nodetable_subnet_limits_per_bucket() {
+        let mut n = KNodeTable::new_with_details(test::make_id(0), 4, 8);
+        n.set_subnet_limits(SubnetLimits {
+            per_bucket: Some(2),
+            per_table: None,
+        });
+        // Bucket 2
+        let first = node_at(4, "10.0.0.1:8000");
+        assert_eq!(Ok(()), n.try_update_at(&first, Instant::now()));
+        assert_eq!(
+            Ok(()),
+            n.try_update_at(&node_at(5, "10.0.0.2:8000"), Instant::now())
+        );
+        assert_eq!(
+            Err(UpdateError::BucketSubnetLimit),
+            n.try_update_at(&node_at(6, "10.0.0.3:8000"), Instant::now())
+        );
+        assert!(n.buckets[2].replacements.is_empty());
+        // Known nodes can still be updated
+        assert!(n.update(&first));
+        // Another network or another bucket
+        assert!(n.update(&node_at(6, "10.0.1.3:8000")));
+        assert!(n.update(&node_at(3, "10.0.0.3:8000")));
+        assert_eq!(4, n.len());
+    }
+
+    #[test]
+    fn test_nodetable_subnet_limits_per_table() {
+        let mut n = KNodeTable::new_with_details(test::make_id(0), 1, 8);
+        n.set_subnet_limits(SubnetLimits {
+            per_bucket: None,
+            per_table: Some(2),
+        });
+        assert!(n.update(&node_at(1, "[2001:db8::1]:8000")));
+        assert!(n.update(&node_at(2, "[2001:db8::2]:8000")));
+        assert_eq!(
+            Err(UpdateError::TableSubnetLimit),
+            n.try_update_at(&node_at(4, "[2001:db8::3]:8000"), Instant::now())
+        );
+        assert!(n.update(&node_at(4, "[2001:db8:0:1::3]:8000")));
+        // Full k-bucket is reported distinctly
+        assert_eq!(
+            Err(UpdateError::BucketFull),
+            n.try_update_at(&node_at(5, "10.0.0.1:8000"), Instant::now())
+        );
+    }
+
+    #[test]
+    fn test_nodetable_subnet_limits_replacements() {
+        let mut n = KNodeTable::new_with_details(test::make_id(0), 2, 8);
+        n.set_subnet_limits(SubnetLimits {
+            per_bucket: None,
+            per_table: Some(1),
+        });
+        // Bucket 2 is full, replacements are within limits when cached
+        assert!(n.update(&node_at(4, "10.0.1.1:8000")));
+        assert!(n.update(&node_at(5, "10.0.2.1:8000")));
+        assert!(!n.update(&node_at(7, "10.0.3.1:8000")));
+        assert!(!n.update(&node_at(6, "10.0.0.1:8000")));
+        // ... but not anymore
+        assert!(n.update(&node_at(1, "10.0.0.2:8000")));
+
+        let popped = n.pop_oldest(Instant::now());
+        assert_eq!(1, popped.len());
+        assert_eq!(test::make_id(4), popped[0].id);
+        assert!(n.contains(&test::make_id(7)));
+        assert!(!n.contains(&test::make_id(6)));
+
+        assert_eq!(test::make_id(5), n.remove(&test::make_id(5)).unwrap().id);
+        assert!(!n.contains(&test::make_id(6)));
+        assert_eq!(2, n.len());
+        // Promoted once the limit allows
+        assert_eq!(test::make_id(1), n.remove(&test::make_id(1)).unwrap().id);
+        assert_eq!(test::make_id(7), n.remove(&test::make_id(7)).unwrap().id);
+        assert!(n.contains(&test::make_id(6)));
+    }
+
+    #[test]
+    fn test_nodetable_subnet_counts() {
+        let mut n = KNodeTable::new_with_details(test::make_id(0), 2, 8);
+        assert!(n.update(&node_at(1, "10.0.0.1:8000")));
+        // Nodes already in the table are counted
+        n.set_subnet_limits(SubnetLimits {
+            per_bucket: None,
+            per_table: Some(1),
+        });
+        assert_eq!(
+            Err(UpdateError::TableSubnetLimit),
+            n.try_update_at(&node_at(2, "10.0.0.2:8000"), Instant::now())
+        );
+        assert!(n.remove(&test::make_id(1)).is_some());
+        assert!(n.update(&node_at(2, "10.0.0.2:8000")));
+
+        // Bucket 2 is full, node 6 is promoted instead of node 4 and goes
+        // back to replacements when node 4 turns out to be alive
+        assert!(n.update(&node_at(4, "10.0.1.1:8000")));
+        assert!(n.update(&node_at(5, "10.0.2.1:8000")));
+        assert!(!n.update(&node_at(6, "10.0.3.1:8000")));
+        let popped = n.pop_oldest(Instant::now());
+        assert_eq!(test::make_id(4), popped[0].id);
+        assert!(n.contains(&test::make_id(6)));
+        assert!(n.update(&popped[0]));
+        assert!(!n.contains(&test::make_id(6)));
+        assert!(n.update(&node_at(3, "10.0.3.2:8000")));
+    }
 }
@@ -13,12 +13,14 @@
 //! for different kind of Rust applications. There will be loosely coupled parts:
 //!
 //! 1. DHT neighborhood table implementation, will be represented by
-//!    `GenericNodeTable` trait and `KNodeTable` implementation.
+//!    `GenericNodeTable` trait and `KNodeTable` and `TreeNodeTable`
+//!    implementations.
 //! 2. Generic DHT logic implementation in `Service` and `service::Handler`
 //!    structures.
 //! 3. Generic bits for implementing protocols in `service::Handler` structure
 //!    and `protocol` module.
-//! 4. (In the future) simple implementations for testing purposes.
+//! 4. Transports for sending requests to other nodes in `transport` module.
+//! 5. Simple implementations for testing purposes, e.g. `transport::memory`.
 
 #![crate_name = "dht"]
 #![crate_type = "lib"]
@@ -28,14 +30,23 @@ extern crate log;
 extern crate rand;
 extern crate rustc_serialize;
 
+pub use base::GenericAPI;
 pub use base::GenericId;
 pub use base::GenericNodeTable;
 pub use base::Node;
-pub use knodetable::KNodeTable;
+pub use id::{Id160, Id256};
+pub use knodetable::{KNodeTable, NodeInfo, SubnetLimits, UpdateError};
 pub use service::Service;
+pub use storage::Storage;
+pub use treetable::TreeNodeTable;
 
 mod base;
+pub mod id;
 mod knodetable;
+mod lookup;
 pub mod protocol;
 pub mod service;
+pub mod storage;
+pub mod transport;
+mod treetable;
 mod utils;
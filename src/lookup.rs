@@ -0,0 +1,220 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! State of an iterative Kademlia lookup.
+//!
+//! The lookup keeps a shortlist of nodes sorted by distance to the target.
+//! It does not do any network calls itself: the caller asks for the next
+//! nodes to query and reports responses and failures back.
+
+use super::GenericId;
+use super::Node;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Waiting,
+    InFlight,
+    Answered,
+    Failed,
+}
+
+struct Candidate<TId, TAddr> {
+    node: Node<TId, TAddr>,
+    distance: TId,
+    state: State,
+}
+
+/// Iterative lookup of nodes closest to a target ID.
+pub struct Lookup<TId, TAddr> {
+    target: TId,
+    count: usize,
+    candidates: Vec<Candidate<TId, TAddr>>,
+}
+
+impl<TId, TAddr> Lookup<TId, TAddr>
+where
+    TId: GenericId,
+    TAddr: Clone,
+{
+    /// Create a lookup for `count` nodes closest to `target`.
+    pub fn new(target: TId, count: usize, nodes: Vec<Node<TId, TAddr>>) -> Lookup<TId, TAddr> {
+        debug_assert!(count > 0);
+        let mut res = Lookup {
+            target,
+            count,
+            candidates: Vec::new(),
+        };
+        res.add_nodes(nodes);
+        res
+    }
+
+    /// Add new nodes to the shortlist, ignoring already known ones.
+    pub fn add_nodes(&mut self, nodes: Vec<Node<TId, TAddr>>) {
+        for node in nodes {
+            if self.candidates.iter().any(|c| c.node.id == node.id) {
+                continue;
+            }
+            let distance = node.id.bitxor(&self.target);
+            let pos = self
+                .candidates
+                .iter()
+                .position(|c| c.distance > distance)
+                .unwrap_or(self.candidates.len());
+            self.candidates.insert(
+                pos,
+                Candidate {
+                    node,
+                    distance,
+                    state: State::Waiting,
+                },
+            );
+        }
+    }
+
+    /// Pick the closest node that was not queried yet and mark it in flight.
+    ///
+    /// Only the `count` closest nodes that did not fail are considered.
+    pub fn next(&mut self) -> Option<Node<TId, TAddr>> {
+        let count = self.count;
+        let candidate = self
+            .candidates
+            .iter_mut()
+            .filter(|c| c.state != State::Failed)
+            .take(count)
+            .find(|c| c.state == State::Waiting);
+        candidate.map(|c| {
+            c.state = State::InFlight;
+            c.node.clone()
+        })
+    }
+
+    /// Record a response from the node with the given ID.
+    pub fn on_response(&mut self, id: &TId, nodes: Vec<Node<TId, TAddr>>) {
+        self.set_state(id, State::Answered);
+        self.add_nodes(nodes);
+    }
+
+    /// Record a failure to get a response from the node with the given ID.
+    pub fn on_failure(&mut self, id: &TId) {
+        self.set_state(id, State::Failed);
+    }
+
+    /// Number of requests that are still in flight.
+    pub fn in_flight(&self) -> usize {
+        self.candidates
+            .iter()
+            .filter(|c| c.state == State::InFlight)
+            .count()
+    }
+
+    /// Check whether the `count` closest alive nodes have all answered.
+    pub fn is_finished(&self) -> bool {
+        self.candidates
+            .iter()
+            .filter(|c| c.state != State::Failed)
+            .take(self.count)
+            .all(|c| c.state == State::Answered)
+    }
+
+    /// Return up to `count` closest nodes that answered.
+    pub fn closest(&self) -> Vec<Node<TId, TAddr>> {
+        self.candidates
+            .iter()
+            .filter(|c| c.state == State::Answered)
+            .take(self.count)
+            .map(|c| c.node.clone())
+            .collect()
+    }
+
+    fn set_state(&mut self, id: &TId, state: State) {
+        if let Some(c) = self.candidates.iter_mut().find(|c| c.node.id == *id) {
+            c.state = state;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+
+    use super::super::Node;
+    use super::Lookup;
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+    fn ids(nodes: &[Node<TestsIdType, net::SocketAddr>]) -> Vec<TestsIdType> {
+        nodes.iter().map(|n| n.id.clone()).collect()
+    }
+
+    #[test]
+    fn test_lookup_next_closest_first() {
+        let mut l = Lookup::new(
+            test::make_id(0),
+            2,
+            vec![
+                test::new_node(test::make_id(4)),
+                test::new_node(test::make_id(1)),
+                test::new_node(test::make_id(2)),
+            ],
+        );
+        assert_eq!(test::make_id(1), l.next().unwrap().id);
+        assert_eq!(test::make_id(2), l.next().unwrap().id);
+        // Only 2 closest nodes are considered
+        assert!(l.next().is_none());
+        assert_eq!(2, l.in_flight());
+        assert!(!l.is_finished());
+    }
+
+    #[test]
+    fn test_lookup_response_adds_nodes() {
+        let mut l = Lookup::new(test::make_id(0), 2, vec![test::new_node(test::make_id(4))]);
+        let node = l.next().unwrap();
+        l.on_response(
+            &node.id,
+            vec![
+                test::new_node(test::make_id(1)),
+                test::new_node(test::make_id(4)),
+            ],
+        );
+        assert_eq!(0, l.in_flight());
+        assert!(!l.is_finished());
+        assert_eq!(test::make_id(1), l.next().unwrap().id);
+        l.on_response(&test::make_id(1), vec![]);
+        assert!(l.is_finished());
+        assert_eq!(vec![test::make_id(1), test::make_id(4)], ids(&l.closest()));
+    }
+
+    #[test]
+    fn test_lookup_failure_skipped() {
+        let mut l = Lookup::new(
+            test::make_id(0),
+            1,
+            vec![
+                test::new_node(test::make_id(1)),
+                test::new_node(test::make_id(2)),
+            ],
+        );
+        assert_eq!(test::make_id(1), l.next().unwrap().id);
+        assert!(l.next().is_none());
+        l.on_failure(&test::make_id(1));
+        assert!(!l.is_finished());
+        assert_eq!(test::make_id(2), l.next().unwrap().id);
+        l.on_response(&test::make_id(2), vec![]);
+        assert!(l.is_finished());
+        assert_eq!(vec![test::make_id(2)], ids(&l.closest()));
+    }
+
+    #[test]
+    fn test_lookup_empty() {
+        let mut l = Lookup::<TestsIdType, net::SocketAddr>::new(test::make_id(0), 3, vec![]);
+        assert!(l.next().is_none());
+        assert!(l.is_finished());
+        assert!(l.closest().is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Minimal bencode encoder and decoder.
+
+use std::collections::BTreeMap;
+
+use super::{ProtocolError, ProtocolResult};
+
+/// Maximum nesting of lists and dictionaries.
+static MAX_DEPTH: usize = 16;
+
+/// Bencoded value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    /// Get a dictionary item by key.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Dict(ref dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+    /// Get a required dictionary item by key.
+    pub fn require(&self, key: &str) -> ProtocolResult<&Value> {
+        self.get(key)
+            .ok_or_else(|| ProtocolError::Malformed(format!("missing key {}", key)))
+    }
+
+    pub fn as_bytes(&self) -> ProtocolResult<&[u8]> {
+        match *self {
+            Value::Bytes(ref bytes) => Ok(bytes),
+            _ => Err(ProtocolError::Malformed("expected a string".to_string())),
+        }
+    }
+    pub fn as_int(&self) -> ProtocolResult<i64> {
+        match *self {
+            Value::Int(value) => Ok(value),
+            _ => Err(ProtocolError::Malformed("expected an integer".to_string())),
+        }
+    }
+    pub fn as_list(&self) -> ProtocolResult<&[Value]> {
+        match *self {
+            Value::List(ref list) => Ok(list),
+            _ => Err(ProtocolError::Malformed("expected a list".to_string())),
+        }
+    }
+}
+
+/// Helper for building dictionaries.
+pub fn dict(items: Vec<(&str, Value)>) -> Value {
+    Value::Dict(
+        items
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+/// Encode a value to binary data.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut result = Vec::new();
+    encode_into(value, &mut result);
+    result
+}
+
+fn encode_into(value: &Value, result: &mut Vec<u8>) {
+    match *value {
+        Value::Int(value) => result.extend(format!("i{}e", value).into_bytes()),
+        Value::Bytes(ref bytes) => encode_bytes(bytes, result),
+        Value::List(ref list) => {
+            result.push(b'l');
+            for item in list {
+                encode_into(item, result);
+            }
+            result.push(b'e');
+        }
+        Value::Dict(ref dict) => {
+            result.push(b'd');
+            for (key, item) in dict {
+                encode_bytes(key, result);
+                encode_into(item, result);
+            }
+            result.push(b'e');
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], result: &mut Vec<u8>) {
+    result.extend(format!("{}:", bytes.len()).into_bytes());
+    result.extend_from_slice(bytes);
+}
+
+/// Decode binary data, which must contain exactly one value.
+pub fn decode(data: &[u8]) -> ProtocolResult<Value> {
+    let mut decoder = Decoder { data, pos: 0 };
+    let value = decoder.value(0)?;
+    if decoder.pos != data.len() {
+        return Err(ProtocolError::Malformed(
+            "trailing data after bencoded value".to_string(),
+        ));
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn value(&mut self, depth: usize) -> ProtocolResult<Value> {
+        if depth > MAX_DEPTH {
+            return Err(ProtocolError::Malformed("nesting is too deep".to_string()));
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let value = self.number(b'e')?;
+                Ok(Value::Int(value))
+            }
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?;
+                    let value = self.value(depth + 1)?;
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?)),
+            other => Err(ProtocolError::Malformed(format!(
+                "unexpected byte {} at position {}",
+                other, self.pos
+            ))),
+        }
+    }
+
+    fn bytes(&mut self) -> ProtocolResult<Vec<u8>> {
+        let len = self.number(b':')?;
+        if len < 0 || len as usize > self.data.len() - self.pos {
+            return Err(ProtocolError::Malformed(format!(
+                "invalid string length {}",
+                len
+            )));
+        }
+        let start = self.pos;
+        self.pos += len as usize;
+        Ok(self.data[start..self.pos].to_vec())
+    }
+
+    fn number(&mut self, terminator: u8) -> ProtocolResult<i64> {
+        let start = self.pos;
+        while self.peek()? != terminator {
+            self.pos += 1;
+        }
+        let text = String::from_utf8_lossy(&self.data[start..self.pos]).into_owned();
+        self.pos += 1;
+        text.parse()
+            .map_err(|_| ProtocolError::Malformed(format!("invalid number {}", text)))
+    }
+
+    fn peek(&self) -> ProtocolResult<u8> {
+        match self.data.get(self.pos) {
+            Some(byte) => Ok(*byte),
+            None => Err(ProtocolError::Malformed(
+                "unexpected end of data".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::ProtocolError;
+    use super::{decode, dict, encode, Value};
+
+    #[test]
+    fn test_encode() {
+        let value = dict(vec![
+            ("t", Value::Bytes(b"aa".to_vec())),
+            (
+                "e",
+                Value::List(vec![Value::Int(201), Value::Bytes(b"x".to_vec())]),
+            ),
+        ]);
+        // Keys are sorted
+        assert_eq!(b"d1:eli201e1:xe1:t2:aae".to_vec(), encode(&value));
+    }
+
+    #[test]
+    fn test_decode() {
+        let value = decode(b"d1:ad2:id3:abce1:q4:ping1:y1:qe").unwrap();
+        assert_eq!(b"ping", value.get("q").unwrap().as_bytes().unwrap());
+        let id = value.get("a").unwrap().get("id").unwrap();
+        assert_eq!(b"abc", id.as_bytes().unwrap());
+        assert!(value.get("t").is_none());
+        assert_eq!(-42, decode(b"i-42e").unwrap().as_int().unwrap());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"d1:ld1:xi1ee1:ml0:e1:zi0ee";
+        assert_eq!(data.to_vec(), encode(&decode(data).unwrap()));
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        for data in &[
+            &b""[..],
+            b"i42",
+            b"5:abc",
+            b"l1:a",
+            b"i4x2e",
+            b"d1:ae",
+            b"i1ei2e",
+            b"-1:a",
+            b"x",
+        ] {
+            match decode(data) {
+                Err(ProtocolError::Malformed(..)) => (),
+                other => panic!("expected error for {:?}, got {:?}", data, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_too_deep() {
+        let data = vec![b'l'; 100];
+        assert!(decode(&data).is_err());
+    }
+}
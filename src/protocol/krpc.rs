@@ -0,0 +1,811 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! KRPC protocol of the BitTorrent Mainline DHT.
+//!
+//! See [BEP 5](http://www.bittorrent.org/beps/bep_0005.html) for details.
+//! Requests are mapped as follows:
+//!
+//! * `ping` - `RequestPayload::Ping`,
+//! * `find_node` - `RequestPayload::FindNode`,
+//! * `get_peers` - `RequestPayload::FindValue`, the value being the list of
+//!   peers for the info hash,
+//! * `announce_peer` - `RequestPayload::Store`, the value being the
+//!   announced peer with the IP of the sender. The TTL is not transferred.
+//!
+//! Services should keep values in a `storage::ListStorage`, so that
+//! announced peers are added to the known ones and expire separately.
+//!
+//! IDs are 20 bytes long (`Id160`), nodes are transferred in the 26 bytes
+//! compact IPv4 format. Transaction IDs of other nodes up to 16 bytes long
+//! are kept in request IDs as well. Up to `MAX_PEERS` random peers are
+//! returned by `get_peers`.
+//!
+//! Tokens received in `get_peers` responses are kept per node and info hash
+//! for `TOKEN_TTL`, tokens in `announce_peer` requests are verified against
+//! the IP of the sender. The secret for tokens changes every
+//! `SECRET_ROTATION`, tokens made with the current or the previous one are
+//! accepted. Error responses do not identify the responder.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+use super::super::{Id160, Node};
+use super::bencode::{self, Value};
+use super::{
+    Protocol, ProtocolError, ProtocolResult, Request, RequestPayload, Response, ResponsePayload,
+};
+
+/// Size of node IDs and info hashes in bytes.
+pub static ID_SIZE: usize = Id160::BYTES;
+/// Size of a node in the compact format.
+static COMPACT_NODE_SIZE: usize = 26;
+/// Size of a peer in the compact format.
+static COMPACT_PEER_SIZE: usize = 6;
+/// Largest message accepted.
+pub static MAX_MESSAGE_SIZE: usize = 4096;
+/// Longest transaction ID accepted in requests, besides full IDs.
+static MAX_TRANSACTION_SIZE: usize = 16;
+/// Time for which tokens received from other nodes are kept.
+pub static TOKEN_TTL: Duration = Duration::from_secs(600);
+/// Time after which the secret for our tokens is replaced.
+pub static SECRET_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Largest number of peers returned in one response.
+pub static MAX_PEERS: usize = 50;
+
+/// Node ID type used by the protocol.
+pub type Id = Id160;
+/// Value type used by the protocol: list of peers.
+pub type Peers = Vec<net::SocketAddrV4>;
+
+// Token with the time it was received, keyed by node address and info hash.
+type Tokens = HashMap<(net::SocketAddr, Id), (Vec<u8>, Instant)>;
+
+/// Implementation of KRPC.
+pub struct KrpcProtocol {
+    secrets: Mutex<Secrets>,
+    // Last token received from every node for every info hash
+    tokens: Mutex<Tokens>,
+}
+
+// Current and previous secrets for tokens with the time of the last change
+struct Secrets {
+    current: u64,
+    previous: u64,
+    rotated: Instant,
+}
+
+impl KrpcProtocol {
+    /// Create a protocol instance with a random secret for tokens.
+    pub fn new() -> KrpcProtocol {
+        KrpcProtocol {
+            secrets: Mutex::new(Secrets {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn token(&self, address: &net::SocketAddr) -> Vec<u8> {
+        self.token_at(address, Instant::now())
+    }
+
+    fn token_at(&self, address: &net::SocketAddr, now: Instant) -> Vec<u8> {
+        let (current, _) = self.secrets(now);
+        make_token(current, address)
+    }
+
+    fn valid_token(&self, token: &[u8], address: &net::SocketAddr, now: Instant) -> bool {
+        let (current, previous) = self.secrets(now);
+        token == &make_token(current, address)[..] || token == &make_token(previous, address)[..]
+    }
+
+    // Current and previous secrets, replaced if they are too old by `now`
+    fn secrets(&self, now: Instant) -> (u64, u64) {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated + SECRET_ROTATION <= now {
+            secrets.previous = if secrets.rotated + SECRET_ROTATION * 2 <= now {
+                rand::random()
+            } else {
+                secrets.current
+            };
+            secrets.current = rand::random();
+            secrets.rotated = now;
+        }
+        (secrets.current, secrets.previous)
+    }
+
+    fn save_token(&self, address: &net::SocketAddr, id: &Id, token: Vec<u8>) {
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, &mut (_, received)| received + TOKEN_TTL > now);
+        let _ = tokens.insert((*address, *id), (token, now));
+    }
+}
+
+impl Default for KrpcProtocol {
+    fn default() -> KrpcProtocol {
+        KrpcProtocol::new()
+    }
+}
+
+fn make_token(secret: u64, address: &net::SocketAddr) -> Vec<u8> {
+    let mut hasher = DefaultHasher::new();
+    secret.hash(&mut hasher);
+    address.ip().hash(&mut hasher);
+    hasher.finish().to_be_bytes()[..4].to_vec()
+}
+
+fn bytes(data: &[u8]) -> Value {
+    Value::Bytes(data.to_vec())
+}
+
+fn parse_id(value: &Value) -> ProtocolResult<Id> {
+    let id = value.as_bytes()?;
+    Id160::from_slice(id).ok_or_else(|| {
+        ProtocolError::Malformed(format!("expected {} bytes ID, got {}", ID_SIZE, id.len()))
+    })
+}
+
+// Full IDs are used as is, shorter transaction IDs are stored at the start
+// of an ID with their size in the last byte.
+fn parse_transaction(value: &Value) -> ProtocolResult<Id> {
+    let data = value.as_bytes()?;
+    if let Some(id) = Id160::from_slice(data) {
+        return Ok(id);
+    }
+    if data.len() > MAX_TRANSACTION_SIZE {
+        return Err(ProtocolError::Malformed(format!(
+            "transaction ID too long: {} bytes",
+            data.len()
+        )));
+    }
+    let mut result = [0u8; Id160::BYTES];
+    result[..data.len()].copy_from_slice(data);
+    result[ID_SIZE - 1] = data.len() as u8;
+    Ok(Id160::from(result))
+}
+
+fn format_transaction(id: &Id) -> Value {
+    let data = id.as_bytes();
+    let size = data[ID_SIZE - 1] as usize;
+    if size <= MAX_TRANSACTION_SIZE && data[size..ID_SIZE - 1].iter().all(|&b| b == 0) {
+        bytes(&data[..size])
+    } else {
+        bytes(data)
+    }
+}
+
+fn parse_port(value: &Value) -> ProtocolResult<u16> {
+    let port = value.as_int()?;
+    if port < 1 || port > i64::from(u16::MAX) {
+        return Err(ProtocolError::Malformed(format!("invalid port {}", port)));
+    }
+    Ok(port as u16)
+}
+
+fn format_peer(peer: &net::SocketAddrV4) -> Vec<u8> {
+    let mut result = peer.ip().octets().to_vec();
+    result.extend_from_slice(&peer.port().to_be_bytes());
+    result
+}
+
+fn parse_peer(data: &[u8]) -> net::SocketAddrV4 {
+    debug_assert!(data.len() == COMPACT_PEER_SIZE);
+    net::SocketAddrV4::new(
+        net::Ipv4Addr::new(data[0], data[1], data[2], data[3]),
+        u16::from_be_bytes([data[4], data[5]]),
+    )
+}
+
+fn format_nodes(nodes: &[Node<Id, net::SocketAddr>]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(nodes.len() * COMPACT_NODE_SIZE);
+    for node in nodes {
+        match node.address {
+            net::SocketAddr::V4(ref address) => {
+                result.extend_from_slice(node.id.as_bytes());
+                result.extend(format_peer(address));
+            }
+            _ => debug!("Skipping node {:?} not suitable for compact format", node),
+        }
+    }
+    result
+}
+
+fn parse_nodes(data: &[u8]) -> ProtocolResult<Vec<Node<Id, net::SocketAddr>>> {
+    if !data.len().is_multiple_of(COMPACT_NODE_SIZE) {
+        return Err(ProtocolError::Malformed(format!(
+            "compact nodes length {} is not a multiple of {}",
+            data.len(),
+            COMPACT_NODE_SIZE
+        )));
+    }
+    Ok(data
+        .chunks(COMPACT_NODE_SIZE)
+        .map(|chunk| Node {
+            id: Id160::from_slice(&chunk[..ID_SIZE]).unwrap(),
+            address: net::SocketAddr::V4(parse_peer(&chunk[ID_SIZE..])),
+        })
+        .collect())
+}
+
+fn parse_peers(values: &[Value]) -> ProtocolResult<Peers> {
+    values
+        .iter()
+        .map(|value| {
+            let data = value.as_bytes()?;
+            if data.len() == COMPACT_PEER_SIZE {
+                Ok(parse_peer(data))
+            } else {
+                Err(ProtocolError::Malformed(format!(
+                    "invalid compact peer length {}",
+                    data.len()
+                )))
+            }
+        })
+        .collect()
+}
+
+fn decode(data: &[u8]) -> ProtocolResult<Value> {
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err(ProtocolError::PayloadTooLarge(data.len()));
+    }
+    bencode::decode(data)
+}
+
+impl Protocol for KrpcProtocol {
+    type Id = Id;
+    type Addr = net::SocketAddr;
+    type Value = Peers;
+
+    fn parse_request(
+        &self,
+        data: &[u8],
+        source: &net::SocketAddr,
+    ) -> ProtocolResult<Request<Id, net::SocketAddr, Peers>> {
+        let message = decode(data)?;
+        if message.require("y")?.as_bytes()? != b"q" {
+            return Err(ProtocolError::Malformed("expected a query".to_string()));
+        }
+        let args = message.require("a")?;
+        let payload = match message.require("q")?.as_bytes()? {
+            b"ping" => RequestPayload::Ping,
+            b"find_node" => RequestPayload::FindNode(parse_id(args.require("target")?)?),
+            b"get_peers" => RequestPayload::FindValue(parse_id(args.require("info_hash")?)?),
+            b"announce_peer" => {
+                let implied = match args.get("implied_port") {
+                    Some(value) => value.as_int()? != 0,
+                    None => false,
+                };
+                let port = if implied {
+                    source.port()
+                } else {
+                    parse_port(args.require("port")?)?
+                };
+                let token = args.require("token")?.as_bytes()?;
+                if !self.valid_token(token, source, Instant::now()) {
+                    return Err(ProtocolError::Malformed("invalid token".to_string()));
+                }
+                let peer = match *source {
+                    net::SocketAddr::V4(ref source) => net::SocketAddrV4::new(*source.ip(), port),
+                    net::SocketAddr::V6(..) => {
+                        return Err(ProtocolError::Malformed(
+                            "IPv6 peers are not supported".to_string(),
+                        ))
+                    }
+                };
+                RequestPayload::Store(parse_id(args.require("info_hash")?)?, vec![peer], None)
+            }
+            other => {
+                return Err(ProtocolError::UnknownMethod(
+                    String::from_utf8_lossy(other).into_owned(),
+                ))
+            }
+        };
+        Ok(Request {
+            caller: Node {
+                id: parse_id(args.require("id")?)?,
+                address: *source,
+            },
+            request_id: parse_transaction(message.require("t")?)?,
+            payload,
+        })
+    }
+
+    fn format_response(&self, response: Response<Id, net::SocketAddr, Peers>) -> Vec<u8> {
+        let t = format_transaction(&response.request.request_id);
+        let mut result = Vec::new();
+        if let Some(ref responder) = response.responder {
+            result.push(("id", bytes(responder.id.as_bytes())));
+        }
+        if let RequestPayload::FindValue(..) = response.request.payload {
+            result.push((
+                "token",
+                bytes(&self.token(&response.request.caller.address)),
+            ));
+        }
+        match response.payload {
+            ResponsePayload::NodesFound(nodes) => {
+                result.push(("nodes", bytes(&format_nodes(&nodes))))
+            }
+            ResponsePayload::ValueFound(mut peers) => {
+                if peers.len() > MAX_PEERS {
+                    rand::thread_rng().shuffle(&mut peers);
+                    peers.truncate(MAX_PEERS);
+                }
+                result.push((
+                    "values",
+                    Value::List(peers.iter().map(|p| Value::Bytes(format_peer(p))).collect()),
+                ))
+            }
+            ResponsePayload::NoResult => {}
+            ResponsePayload::Error(code, message) => {
+                return bencode::encode(&bencode::dict(vec![
+                    ("t", t),
+                    ("y", bytes(b"e")),
+                    (
+                        "e",
+                        Value::List(vec![Value::Int(i64::from(code)), bytes(message.as_bytes())]),
+                    ),
+                ]));
+            }
+        }
+        bencode::encode(&bencode::dict(vec![
+            ("t", t),
+            ("y", bytes(b"r")),
+            ("r", bencode::dict(result)),
+        ]))
+    }
+
+    fn format_request(
+        &self,
+        request: &Request<Id, net::SocketAddr, Peers>,
+        address: &net::SocketAddr,
+    ) -> Vec<u8> {
+        let mut args = vec![("id", bytes(request.caller.id.as_bytes()))];
+        let method: &[u8] = match request.payload {
+            RequestPayload::Ping => b"ping",
+            RequestPayload::FindNode(ref id) => {
+                args.push(("target", bytes(id.as_bytes())));
+                b"find_node"
+            }
+            RequestPayload::FindValue(ref id) => {
+                args.push(("info_hash", bytes(id.as_bytes())));
+                b"get_peers"
+            }
+            RequestPayload::Store(ref id, ref peers, _) => {
+                args.push(("info_hash", bytes(id.as_bytes())));
+                match peers.first() {
+                    Some(peer) if peer.port() != 0 => {
+                        args.push(("port", Value::Int(i64::from(peer.port()))))
+                    }
+                    _ => args.push(("implied_port", Value::Int(1))),
+                }
+                let token = self
+                    .tokens
+                    .lock()
+                    .unwrap()
+                    .get(&(*address, *id))
+                    .filter(|&&(_, received)| received + TOKEN_TTL > Instant::now())
+                    .map(|(token, _)| token.clone());
+                args.push(("token", bytes(&token.unwrap_or_default())));
+                b"announce_peer"
+            }
+        };
+        bencode::encode(&bencode::dict(vec![
+            ("t", format_transaction(&request.request_id)),
+            ("y", bytes(b"q")),
+            ("q", bytes(method)),
+            ("a", bencode::dict(args)),
+        ]))
+    }
+
+    fn response_id(&self, data: &[u8]) -> Option<Id> {
+        let message = decode(data).ok()?;
+        match message.get("y")?.as_bytes().ok()? {
+            b"r" | b"e" => parse_transaction(message.get("t")?).ok(),
+            _ => None,
+        }
+    }
+
+    fn parse_response(
+        &self,
+        request: Request<Id, net::SocketAddr, Peers>,
+        data: &[u8],
+        source: &net::SocketAddr,
+    ) -> ProtocolResult<Response<Id, net::SocketAddr, Peers>> {
+        let message = decode(data)?;
+        if message.require("y")?.as_bytes()? == b"e" {
+            let error = message.require("e")?.as_list()?;
+            if error.len() != 2 {
+                return Err(ProtocolError::Malformed("invalid error".to_string()));
+            }
+            let message = String::from_utf8_lossy(error[1].as_bytes()?).into_owned();
+            return Ok(Response {
+                request,
+                responder: None,
+                payload: ResponsePayload::Error(error[0].as_int()? as u32, message),
+            });
+        }
+
+        let result = message.require("r")?;
+        let payload = match request.payload {
+            RequestPayload::Ping | RequestPayload::Store(..) => ResponsePayload::NoResult,
+            RequestPayload::FindNode(..) => {
+                ResponsePayload::NodesFound(parse_nodes(result.require("nodes")?.as_bytes()?)?)
+            }
+            RequestPayload::FindValue(ref id) => {
+                if let Some(token) = result.get("token") {
+                    self.save_token(source, id, token.as_bytes()?.to_vec());
+                }
+                match result.get("values") {
+                    Some(values) => ResponsePayload::ValueFound(parse_peers(values.as_list()?)?),
+                    None => ResponsePayload::NodesFound(parse_nodes(
+                        result.require("nodes")?.as_bytes()?,
+                    )?),
+                }
+            }
+        };
+        Ok(Response {
+            request,
+            responder: Some(Node {
+                id: parse_id(result.require("id")?)?,
+                address: *source,
+            }),
+            payload,
+        })
+    }
+
+    fn format_error(&self, data: &[u8], error: &ProtocolError) -> Option<Vec<u8>> {
+        let message = bencode::decode(data).ok()?;
+        let t = message.get("t")?.as_bytes().ok()?;
+        Some(bencode::encode(&bencode::dict(vec![
+            ("t", bytes(t)),
+            ("y", bytes(b"e")),
+            (
+                "e",
+                Value::List(vec![
+                    Value::Int(i64::from(error.code())),
+                    bytes(error.to_string().as_bytes()),
+                ]),
+            ),
+        ])))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+    use std::time::Instant;
+
+    use super::super::super::Id160;
+    use super::super::super::Node;
+    use super::super::{
+        Protocol, ProtocolError, Request, RequestPayload, Response, ResponsePayload,
+    };
+    use super::{Id, KrpcProtocol, MAX_PEERS, SECRET_ROTATION};
+
+    // Examples from BEP 5
+    static PING_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    static PING_RESPONSE: &[u8] = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+    static FIND_NODE_QUERY: &[u8] =
+        b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe";
+    static GET_PEERS_QUERY: &[u8] =
+        b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
+    static ANNOUNCE_PEER_QUERY: &[u8] =
+        b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    static ERROR_RESPONSE: &[u8] = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+
+    fn id(s: &str) -> Id {
+        Id160::from_slice(s.as_bytes()).unwrap()
+    }
+
+    fn transaction(s: &str) -> Id {
+        super::parse_transaction(&super::bytes(s.as_bytes())).unwrap()
+    }
+
+    fn node(s: &str, port: u16) -> Node<Id, net::SocketAddr> {
+        Node {
+            id: id(s),
+            address: net::SocketAddr::V4(net::SocketAddrV4::new(
+                net::Ipv4Addr::new(10, 0, 0, 1),
+                port,
+            )),
+        }
+    }
+
+    fn client_addr() -> net::SocketAddr {
+        node("abcdefghij0123456789", 6881).address
+    }
+
+    fn server_addr() -> net::SocketAddr {
+        node("mnopqrstuvwxyz123456", 6882).address
+    }
+
+    fn request(
+        payload: RequestPayload<Id, super::Peers>,
+    ) -> Request<Id, net::SocketAddr, super::Peers> {
+        Request {
+            caller: node("abcdefghij0123456789", 6881),
+            request_id: transaction("aa"),
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_ping() {
+        let p = KrpcProtocol::new();
+        let req = p.parse_request(PING_QUERY, &client_addr()).unwrap();
+        assert_eq!(transaction("aa"), req.request_id);
+        assert_eq!(id("abcdefghij0123456789"), req.caller.id);
+        assert_eq!(client_addr(), req.caller.address);
+        match req.payload {
+            RequestPayload::Ping => (),
+            _ => panic!("wrong payload"),
+        }
+        assert_eq!(PING_QUERY.to_vec(), p.format_request(&req, &server_addr()));
+        assert!(p.response_id(PING_QUERY).is_none());
+
+        let response = Response {
+            request: req,
+            responder: Some(node("mnopqrstuvwxyz123456", 6882)),
+            payload: ResponsePayload::NoResult,
+        };
+        assert_eq!(PING_RESPONSE.to_vec(), p.format_response(response));
+        assert_eq!(Some(transaction("aa")), p.response_id(PING_RESPONSE));
+    }
+
+    #[test]
+    fn test_find_node() {
+        let p = KrpcProtocol::new();
+        let req = p.parse_request(FIND_NODE_QUERY, &client_addr()).unwrap();
+        match req.payload {
+            RequestPayload::FindNode(ref target) => assert_eq!(id("mnopqrstuvwxyz123456"), *target),
+            _ => panic!("wrong payload"),
+        }
+        assert_eq!(
+            FIND_NODE_QUERY.to_vec(),
+            p.format_request(&req, &server_addr())
+        );
+
+        let response = Response {
+            request: req,
+            responder: Some(node("mnopqrstuvwxyz123456", 6882)),
+            payload: ResponsePayload::NodesFound(vec![node("0123456789abcdefghij", 6883)]),
+        };
+        let data = p.format_response(response);
+        let response = p
+            .parse_response(
+                request(RequestPayload::FindNode(id("mnopqrstuvwxyz123456"))),
+                &data,
+                &server_addr(),
+            )
+            .unwrap();
+        assert_eq!(
+            node("mnopqrstuvwxyz123456", 6882).id,
+            response.responder.unwrap().id
+        );
+        match response.payload {
+            ResponsePayload::NodesFound(nodes) => {
+                assert_eq!(1, nodes.len());
+                assert_eq!(node("0123456789abcdefghij", 6883).id, nodes[0].id);
+                assert_eq!(node("0123456789abcdefghij", 6883).address, nodes[0].address);
+            }
+            _ => panic!("wrong payload"),
+        }
+    }
+
+    #[test]
+    fn test_get_peers_and_announce() {
+        let server = KrpcProtocol::new();
+        let client = KrpcProtocol::new();
+        let info_hash = id("mnopqrstuvwxyz123456");
+
+        let req = server
+            .parse_request(GET_PEERS_QUERY, &client_addr())
+            .unwrap();
+        match req.payload {
+            RequestPayload::FindValue(ref hash) => assert_eq!(info_hash, *hash),
+            _ => panic!("wrong payload"),
+        }
+        let peer = net::SocketAddrV4::new(net::Ipv4Addr::new(10, 0, 0, 2), 6881);
+        let data = server.format_response(Response {
+            request: req,
+            responder: Some(node("mnopqrstuvwxyz123456", 6882)),
+            payload: ResponsePayload::ValueFound(vec![peer]),
+        });
+        let response = client
+            .parse_response(
+                request(RequestPayload::FindValue(info_hash)),
+                &data,
+                &server_addr(),
+            )
+            .unwrap();
+        match response.payload {
+            ResponsePayload::ValueFound(peers) => assert_eq!(vec![peer], peers),
+            _ => panic!("wrong payload"),
+        }
+
+        // The token from get_peers is used for announce_peer to the same node
+        let announce = request(RequestPayload::Store(info_hash, vec![peer], None));
+        let data = client.format_request(&announce, &server_addr());
+        let token = server.token(&client_addr());
+        let expected = format!("5:token{}:", token.len()).into_bytes();
+        assert!(data.windows(expected.len()).any(|w| w == &expected[..]));
+        let req = server.parse_request(&data, &client_addr()).unwrap();
+        match req.payload {
+            RequestPayload::Store(hash, peers, _) => {
+                assert_eq!(info_hash, hash);
+                // The IP is the one of the sender
+                let expected = net::SocketAddrV4::new(net::Ipv4Addr::new(10, 0, 0, 1), 6881);
+                assert_eq!(vec![expected], peers);
+            }
+            _ => panic!("wrong payload"),
+        }
+
+        // The token is only valid for the IP it was given to
+        let other = "10.0.0.3:6881".parse().unwrap();
+        match server.parse_request(&data, &other) {
+            Err(ProtocolError::Malformed(..)) => (),
+            _ => panic!("expected invalid token"),
+        }
+        // No token was received from other nodes
+        let data = client.format_request(&announce, &other);
+        match server.parse_request(&data, &client_addr()) {
+            Err(ProtocolError::Malformed(..)) => (),
+            _ => panic!("expected invalid token"),
+        }
+    }
+
+    #[test]
+    fn test_token_rotation() {
+        let p = KrpcProtocol::new();
+        let now = Instant::now();
+        let token = p.token_at(&client_addr(), now);
+        assert!(p.valid_token(&token, &client_addr(), now));
+        assert!(p.valid_token(&token, &client_addr(), now + SECRET_ROTATION));
+        // Made with the secret before the previous one
+        assert!(!p.valid_token(&token, &client_addr(), now + SECRET_ROTATION * 2));
+        let mut data = ANNOUNCE_PEER_QUERY.to_vec();
+        let pos = data.windows(8).position(|w| w == b"aoeusnth").unwrap();
+        data.splice(pos - 2..pos + 8, [&b"4:"[..], &token[..]].concat());
+        match p.parse_request(&data, &client_addr()) {
+            Err(ProtocolError::Malformed(..)) => (),
+            _ => panic!("expected invalid token"),
+        }
+    }
+
+    #[test]
+    fn test_too_many_peers() {
+        let p = KrpcProtocol::new();
+        let req = p.parse_request(GET_PEERS_QUERY, &client_addr()).unwrap();
+        let peers: Vec<_> = (0..200)
+            .map(|i| net::SocketAddrV4::new(net::Ipv4Addr::new(10, 0, 1, i as u8), 6881))
+            .collect();
+        let data = p.format_response(Response {
+            request: req,
+            responder: Some(node("mnopqrstuvwxyz123456", 6882)),
+            payload: ResponsePayload::ValueFound(peers.clone()),
+        });
+        let response = KrpcProtocol::new()
+            .parse_response(
+                request(RequestPayload::FindValue(id("mnopqrstuvwxyz123456"))),
+                &data,
+                &server_addr(),
+            )
+            .unwrap();
+        match response.payload {
+            ResponsePayload::ValueFound(found) => {
+                assert_eq!(MAX_PEERS, found.len());
+                assert!(found.iter().all(|peer| peers.contains(peer)));
+            }
+            _ => panic!("wrong payload"),
+        }
+    }
+
+    #[test]
+    fn test_announce_implied_port() {
+        let p = KrpcProtocol::new();
+        match p.parse_request(ANNOUNCE_PEER_QUERY, &client_addr()) {
+            Err(ProtocolError::Malformed(..)) => (),
+            _ => panic!("expected invalid token"),
+        }
+
+        let source = "10.0.0.1:7000".parse().unwrap();
+        let token = p.token(&source);
+        let mut data = ANNOUNCE_PEER_QUERY.to_vec();
+        let pos = data.windows(8).position(|w| w == b"aoeusnth").unwrap();
+        data.splice(pos - 2..pos + 8, [&b"4:"[..], &token[..]].concat());
+        let req = p.parse_request(&data, &source).unwrap();
+        match req.payload {
+            RequestPayload::Store(hash, peers, _) => {
+                assert_eq!(id("mnopqrstuvwxyz123456"), hash);
+                let expected = net::SocketAddrV4::new(net::Ipv4Addr::new(10, 0, 0, 1), 7000);
+                assert_eq!(vec![expected], peers);
+            }
+            _ => panic!("wrong payload"),
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let p = KrpcProtocol::new();
+        assert_eq!(Some(transaction("aa")), p.response_id(ERROR_RESPONSE));
+        let response = p
+            .parse_response(
+                request(RequestPayload::Ping),
+                ERROR_RESPONSE,
+                &server_addr(),
+            )
+            .unwrap();
+        assert!(response.responder.is_none());
+        match response.payload {
+            ResponsePayload::Error(code, message) => {
+                assert_eq!(201, code);
+                assert_eq!("A Generic Error Ocurred", message);
+            }
+            _ => panic!("wrong payload"),
+        }
+
+        let data = p.format_response(Response {
+            request: request(RequestPayload::Ping),
+            responder: Some(node("mnopqrstuvwxyz123456", 6882)),
+            payload: ResponsePayload::Error(201, "A Generic Error Ocurred".to_string()),
+        });
+        assert_eq!(ERROR_RESPONSE.to_vec(), data);
+    }
+
+    #[test]
+    fn test_transaction_ids() {
+        let p = KrpcProtocol::new();
+        // Our request IDs are sent as is
+        let mut req = request(RequestPayload::Ping);
+        req.request_id = id("0123456789abcdefghij");
+        let data = p.format_request(&req, &server_addr());
+        let reply = p.format_response(Response {
+            request: p.parse_request(&data, &client_addr()).unwrap(),
+            responder: Some(node("mnopqrstuvwxyz123456", 6882)),
+            payload: ResponsePayload::NoResult,
+        });
+        assert_eq!(Some(req.request_id), p.response_id(&reply));
+
+        let query = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t17:0123456789abcdefg1:y1:qe";
+        match p.parse_request(query, &client_addr()) {
+            Err(ProtocolError::Malformed(..)) => (),
+            _ => panic!("long transaction ID accepted"),
+        }
+    }
+
+    #[test]
+    fn test_parse_request_errors() {
+        let p = KrpcProtocol::new();
+        let data = b"d1:ad2:id20:abcdefghij0123456789e1:q5:dance1:t2:aa1:y1:qe";
+        let err = p.parse_request(data, &client_addr()).err().unwrap();
+        assert_eq!(ProtocolError::UnknownMethod("dance".to_string()), err);
+        let reply = p.format_error(data, &err).unwrap();
+        assert_eq!(Some(transaction("aa")), p.response_id(&reply));
+
+        let short_id = b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe";
+        match p.parse_request(short_id, &client_addr()) {
+            Err(ProtocolError::Malformed(..)) => (),
+            _ => panic!("expected malformed error"),
+        }
+        match p.parse_request(&vec![b'x'; 10000], &client_addr()) {
+            Err(ProtocolError::PayloadTooLarge(10000)) => (),
+            _ => panic!("expected too large error"),
+        }
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Generic protocol bits for implementing custom protocols.
+
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+use super::{GenericId, Node};
+
+mod bencode;
+pub mod krpc;
+
+/// Generic error, e.g. a rejected value.
+pub const ERROR_GENERIC: u32 = 201;
+/// Internal error of the responding node.
+pub const ERROR_SERVER: u32 = 202;
+/// Request could not be parsed.
+pub const ERROR_PROTOCOL: u32 = 203;
+/// Request method is not known.
+pub const ERROR_METHOD: u32 = 204;
+
+/// Payload in the request.
+pub enum RequestPayload<TId, TValue> {
+    Ping,
+    FindNode(TId),
+    FindValue(TId),
+    /// Store the value, optionally for no longer than the given time.
+    Store(TId, TValue, Option<Duration>),
+}
+
+/// Request structure.
+pub struct Request<TId, TAddr, TValue> {
+    pub caller: Node<TId, TAddr>,
+    pub request_id: TId,
+    pub payload: RequestPayload<TId, TValue>,
+}
+
+/// Payload in the response.
+pub enum ResponsePayload<TId, TAddr, TValue> {
+    NodesFound(Vec<Node<TId, TAddr>>),
+    ValueFound(TValue),
+    NoResult,
+    /// Request was rejected, with error code and message.
+    Error(u32, String),
+}
+
+/// Response structure.
+pub struct Response<TId, TAddr, TValue> {
+    pub request: Request<TId, TAddr, TValue>,
+    /// Node that answered, `None` if the response does not identify it
+    /// (e.g. KRPC errors).
+    pub responder: Option<Node<TId, TAddr>>,
+    pub payload: ResponsePayload<TId, TAddr, TValue>,
+}
+
+/// Error parsing binary data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Data cannot be parsed, with explanation.
+    Malformed(String),
+    /// Request method is not known.
+    UnknownMethod(String),
+    /// Protocol version is not supported.
+    UnsupportedVersion(String),
+    /// Data is larger than allowed, with its size.
+    PayloadTooLarge(usize),
+}
+
+/// Result of parsing binary data.
+pub type ProtocolResult<T> = Result<T, ProtocolError>;
+
+impl ProtocolError {
+    /// Error code to report to the peer.
+    pub fn code(&self) -> u32 {
+        match *self {
+            ProtocolError::UnknownMethod(..) => ERROR_METHOD,
+            _ => ERROR_PROTOCOL,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::Malformed(ref msg) => write!(f, "Malformed data: {}", msg),
+            ProtocolError::UnknownMethod(ref method) => write!(f, "Unknown method {}", method),
+            ProtocolError::UnsupportedVersion(ref version) => {
+                write!(f, "Unsupported protocol version {}", version)
+            }
+            ProtocolError::PayloadTooLarge(size) => write!(f, "Payload too large: {} bytes", size),
+        }
+    }
+}
+
+impl error::Error for ProtocolError {}
+
+/// Trait for a protocol implementation.
+///
+/// Server side parses requests and formats responses, client side formats
+/// requests and parses responses to them. Responses are correlated with
+/// requests by `Request::request_id`, see `response_id`.
+///
+/// Transports pass the address of the other node to methods dealing with
+/// its messages, and may override addresses of the caller and the responder
+/// with ones they know better, e.g. `transport::udp` uses the datagram source.
+pub trait Protocol: Send {
+    /// Value type.
+    type Id: GenericId;
+    type Addr: Send + Sync;
+    type Value: Send + Sync;
+    /// Parse request received from `source` from binary data.
+    fn parse_request(
+        &self,
+        data: &[u8],
+        source: &Self::Addr,
+    ) -> ProtocolResult<Request<Self::Id, Self::Addr, Self::Value>>;
+    /// Format response to binary data.
+    fn format_response(&self, response: Response<Self::Id, Self::Addr, Self::Value>) -> Vec<u8>;
+    /// Format request to be sent to `address` to binary data.
+    fn format_request(
+        &self,
+        request: &Request<Self::Id, Self::Addr, Self::Value>,
+        address: &Self::Addr,
+    ) -> Vec<u8>;
+    /// Get ID of the request that binary data is a response to.
+    ///
+    /// Returns `None` if data is not a response.
+    fn response_id(&self, data: &[u8]) -> Option<Self::Id>;
+    /// Parse response to the given request received from `source` from
+    /// binary data.
+    fn parse_response(
+        &self,
+        request: Request<Self::Id, Self::Addr, Self::Value>,
+        data: &[u8],
+        source: &Self::Addr,
+    ) -> ProtocolResult<Response<Self::Id, Self::Addr, Self::Value>>;
+    /// Format error response to binary data that failed to parse.
+    ///
+    /// Returns `None` if the peer cannot be answered, which is the default.
+    fn format_error(&self, _data: &[u8], _error: &ProtocolError) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+    use std::time::Duration;
+
+    use super::super::utils::test;
+    use super::{Protocol, ProtocolError, Request, RequestPayload, Response, ResponsePayload};
+
+    fn new_request(
+        payload: RequestPayload<test::IdType, String>,
+    ) -> Request<test::IdType, net::SocketAddr, String> {
+        Request {
+            caller: test::new_node(test::make_id(42)),
+            request_id: test::make_id(1),
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        let p = test::TestProtocol;
+        let data = p.format_request(
+            &new_request(RequestPayload::Store(
+                test::make_id(3),
+                "foobar".to_string(),
+                Some(Duration::from_secs(60)),
+            )),
+            &test::make_addr(8009),
+        );
+        let request = p.parse_request(&data, &test::make_addr(8008)).unwrap();
+        assert_eq!(test::make_id(1), request.request_id);
+        assert_eq!(test::make_id(42), request.caller.id);
+        assert_eq!(test::make_addr(8008), request.caller.address);
+        match request.payload {
+            RequestPayload::Store(id, value, ttl) => {
+                assert_eq!(test::make_id(3), id);
+                assert_eq!("foobar", value);
+                assert_eq!(Some(Duration::from_secs(60)), ttl);
+            }
+            _ => panic!("wrong payload"),
+        }
+        assert!(p.response_id(&data).is_none());
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        let p = test::TestProtocol;
+        let response = Response {
+            request: new_request(RequestPayload::FindNode(test::make_id(3))),
+            responder: Some(test::new_node_with_port(test::make_id(43), 8009)),
+            payload: ResponsePayload::NodesFound(vec![test::new_node(test::make_id(44))]),
+        };
+        let data = p.format_response(response);
+
+        assert_eq!(Some(test::make_id(1)), p.response_id(&data));
+        let request = new_request(RequestPayload::FindNode(test::make_id(3)));
+        let response = p
+            .parse_response(request, &data, &test::make_addr(8009))
+            .unwrap();
+        let responder = response.responder.unwrap();
+        assert_eq!(test::make_id(43), responder.id);
+        assert_eq!(test::make_addr(8009), responder.address);
+        match response.payload {
+            ResponsePayload::NodesFound(nodes) => {
+                assert_eq!(1, nodes.len());
+                assert_eq!(test::make_id(44), nodes[0].id);
+            }
+            _ => panic!("wrong payload"),
+        }
+    }
+
+    #[test]
+    fn test_parse_request_errors() {
+        let p = test::TestProtocol;
+        match p.parse_request(b"\xff\xfe", &test::make_addr(8008)) {
+            Err(ProtocolError::Malformed(..)) => (),
+            _ => panic!("expected malformed error"),
+        }
+        let data = b"REQ 01 2a@127.0.0.1:8008 DANCE";
+        let err = p.parse_request(data, &test::make_addr(8008)).err().unwrap();
+        assert_eq!(ProtocolError::UnknownMethod("DANCE".to_string()), err);
+        assert_eq!(super::ERROR_METHOD, err.code());
+        let reply = p.format_error(data, &err).unwrap();
+        assert_eq!(Some(test::make_id(1)), p.response_id(&reply));
+        let response = p
+            .parse_response(
+                new_request(RequestPayload::Ping),
+                &reply,
+                &test::make_addr(8009),
+            )
+            .unwrap();
+        assert!(response.responder.is_none());
+    }
+
+    #[test]
+    fn test_error_response_roundtrip() {
+        let p = test::TestProtocol;
+        let response = Response {
+            request: new_request(RequestPayload::Ping),
+            responder: Some(test::new_node(test::make_id(43))),
+            payload: ResponsePayload::Error(super::ERROR_GENERIC, "go away".to_string()),
+        };
+        let data = p.format_response(response);
+        let response = p
+            .parse_response(
+                new_request(RequestPayload::Ping),
+                &data,
+                &test::make_addr(8009),
+            )
+            .unwrap();
+        match response.payload {
+            ResponsePayload::Error(code, message) => {
+                assert_eq!(super::ERROR_GENERIC, code);
+                assert_eq!("go away", message);
+            }
+            _ => panic!("wrong payload"),
+        }
+    }
+
+    #[test]
+    fn test_protocol_error_display() {
+        assert_eq!(
+            "Payload too large: 100500 bytes",
+            ProtocolError::PayloadTooLarge(100500).to_string()
+        );
+        assert_eq!(
+            super::ERROR_PROTOCOL,
+            ProtocolError::UnsupportedVersion("2".to_string()).code()
+        );
+    }
+}
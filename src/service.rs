@@ -9,13 +9,39 @@
 
 //! Protocol-agnostic service implementation
 
+use std::cmp;
 use std::collections::HashMap;
-use std::marker;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime};
 
-use super::{GenericId, GenericNodeTable, Node};
+use rand::prng::XorShiftRng;
+use rand::{self, Rng, SeedableRng};
+use rustc_serialize::json;
+
+use super::lookup::Lookup;
+use super::protocol::{Request, RequestPayload, Response, ResponsePayload, ERROR_GENERIC};
+use super::storage::{MemoryStorage, Storage};
+use super::transport::Transport;
+use super::{GenericId, GenericNodeTable, KNodeTable, Node, UpdateError};
 
 static MAX_NODE_COUNT: usize = 16;
+/// Number of parallel requests during lookups.
+static ALPHA: usize = 3;
+/// Buckets without lookups for this time are refreshed by `refresh_buckets`.
+pub static REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+/// Values not received or republished for this time are sent again to the
+/// closest nodes by `republish`.
+pub static REPUBLISH_INTERVAL: Duration = Duration::from_secs(3600);
+/// Default time for which received values are stored.
+pub static VALUE_TTL: Duration = Duration::from_secs(24 * 3600);
 
 /// Result of the find operations - either data or nodes closest to it.
 #[derive(Debug)]
@@ -25,114 +51,831 @@ pub enum FindResult<TId, TAddr, TData> {
     Nothing,
 }
 
+/// What to do with the lookup after a response.
+enum LookupStep<TId, TAddr> {
+    /// Node answered with these nodes.
+    Continue(Vec<Node<TId, TAddr>>),
+    /// Node did not answer properly.
+    Fail,
+    /// Lookup is over.
+    Stop,
+}
+
+/// Error returned by `Service::bootstrap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootstrapError {
+    /// None of the seed nodes answered.
+    NoSeedAnswered,
+}
+
+impl fmt::Display for BootstrapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BootstrapError::NoSeedAnswered => write!(f, "none of the seed nodes answered"),
+        }
+    }
+}
+
+impl Error for BootstrapError {}
+
+/// Error returned by `Service::put`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PutError {
+    /// Fewer nodes than required acknowledged the value, with their number.
+    QuorumNotReached(usize),
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PutError::QuorumNotReached(count) => {
+                write!(f, "only {} nodes acknowledged the value", count)
+            }
+        }
+    }
+}
+
+impl Error for PutError {}
+
+/// Function checking whether a value can be stored under the given key.
+pub type StoreValidator<TId, TData> = dyn Fn(&TId, &TData) -> bool + Send + Sync;
+
+type SharedValidator<TId, TData> = Arc<RwLock<Option<Box<StoreValidator<TId, TData>>>>>;
+
+type SharedStorage<TId, TData> = Arc<RwLock<Box<dyn Storage<TId, TData>>>>;
+
+/// Sender of responses from transport callbacks back to the service.
+///
+/// Sends a failure if the transport drops the callback without calling it,
+/// so that waiting for responses never blocks forever.
+/// The round-trip time is measured with the clock of the transport.
+struct ResponseSender<TTag, TResponse> {
+    sender: mpsc::Sender<(TTag, Option<TResponse>, Duration)>,
+    tag: Option<TTag>,
+    sent: Instant,
+    clock: Box<dyn Fn() -> Instant + Send>,
+}
+
+impl<TTag, TResponse> ResponseSender<TTag, TResponse> {
+    fn new<TId, TAddr, TData, TTransport>(
+        sender: &mpsc::Sender<(TTag, Option<TResponse>, Duration)>,
+        tag: TTag,
+        transport: &TTransport,
+    ) -> ResponseSender<TTag, TResponse>
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        ResponseSender {
+            sender: sender.clone(),
+            tag: Some(tag),
+            sent: transport.now(),
+            clock: transport.clock(),
+        }
+    }
+
+    fn send(mut self, response: Option<TResponse>) {
+        self.send_once(response);
+    }
+
+    fn send_once(&mut self, response: Option<TResponse>) {
+        if let Some(tag) = self.tag.take() {
+            // The receiver is gone if the caller is not waiting any more
+            let rtt = (self.clock)().saturating_duration_since(self.sent);
+            let _ = self.sender.send((tag, response, rtt));
+        }
+    }
+}
+
+impl<TTag, TResponse> Drop for ResponseSender<TTag, TResponse> {
+    fn drop(&mut self) {
+        self.send_once(None);
+    }
+}
+
+/// Where and how often the node table is saved.
+struct Persistence {
+    path: PathBuf,
+    interval: Duration,
+    last_saved: Instant,
+}
+
+/// What is known about a value received from another node.
+struct Received<TId> {
+    // Last time it was received or republished
+    at: Instant,
+    // Node that stored it first, it may renew the value
+    publisher: TId,
+    // Copies cached after lookups are not republished
+    cached: bool,
+}
+
 /// Handler - implementation of DHT requests.
+///
+/// Cloned handlers share the node table and the data, so they can be used
+/// to serve requests from other threads.
 pub struct Handler<TId, TAddr, TNodeTable, TData>
 where
     TId: GenericId,
     TNodeTable: GenericNodeTable<TId, TAddr>,
-    TData: Send + Sync + Clone,
+    TData: Send + Sync + Clone + PartialEq,
 {
-    _phantom: marker::PhantomData<TAddr>,
     node_id: TId,
+    address: TAddr,
     table: Arc<RwLock<TNodeTable>>,
-    data: Arc<RwLock<HashMap<TId, TData>>>,
-    clean_needed: bool,
+    data: SharedStorage<TId, TData>,
+    validator: SharedValidator<TId, TData>,
+    value_ttl: Arc<RwLock<Duration>>,
+    received: Arc<RwLock<HashMap<TId, Received<TId>>>>,
+    clean_needed: Arc<AtomicBool>,
+    // Generator of request IDs and random IDs for lookups
+    rng: Arc<Mutex<XorShiftRng>>,
+    // Instant and system time at creation, to convert one to another
+    epoch: (Instant, SystemTime),
 }
 
 /// Protocol agnostic DHT service.
 ///
 /// Its type parameters are `TNodeTable` - the node table implementation
-/// (see e.g. `KNodeTable`) and `TData` - stored data type.
+/// (see e.g. `KNodeTable`) and `TData` - stored data type, values are
+/// compared to let repeated stores renew them. Data is kept in a
+/// `storage::MemoryStorage` unless another `storage::Storage` is set.
 ///
-/// The service starts a network listening loop in a separate thread.
+/// The service does not do any networking itself: incoming requests are
+/// passed to its `Handler` and outgoing requests are sent through a
+/// `transport::Transport`, see e.g. `transport::udp`.
 pub struct Service<TId, TAddr, TNodeTable, TData>
 where
     TId: GenericId,
     TNodeTable: GenericNodeTable<TId, TAddr>,
-    TData: Send + Sync + Clone,
+    TData: Send + Sync + Clone + PartialEq,
 {
     handler: Handler<TId, TAddr, TNodeTable, TData>,
     node_id: TId,
+    address: TAddr,
     table: Arc<RwLock<TNodeTable>>,
-    data: Arc<RwLock<HashMap<TId, TData>>>,
+    data: SharedStorage<TId, TData>,
+    persistence: Option<Persistence>,
+    put_quorum: usize,
 }
 
 impl<TId, TAddr, TNodeTable, TData> Service<TId, TAddr, TNodeTable, TData>
 where
-    TId: GenericId,
-    TAddr: Send + Sync,
+    TId: GenericId + 'static,
+    TAddr: Clone + Send + Sync,
     TNodeTable: GenericNodeTable<TId, TAddr>,
-    TData: Send + Sync + Clone,
+    TData: Send + Sync + Clone + PartialEq + 'static,
 {
     /// Create a service with a random ID.
-    pub fn new(node_table: TNodeTable) -> Service<TId, TAddr, TNodeTable, TData> {
+    ///
+    /// `address` is the network address other nodes use to reach us.
+    pub fn new(node_table: TNodeTable, address: TAddr) -> Service<TId, TAddr, TNodeTable, TData> {
         let node_id = node_table.random_id();
-        Service::new_with_id(node_table, node_id)
+        Service::new_with_id(node_table, node_id, address)
     }
     /// Create a service with a given ID.
     pub fn new_with_id(
         node_table: TNodeTable,
         node_id: TId,
+        address: TAddr,
     ) -> Service<TId, TAddr, TNodeTable, TData> {
         let table = Arc::new(RwLock::new(node_table));
-        let data = Arc::new(RwLock::new(HashMap::new()));
+        let data: SharedStorage<TId, TData> = Arc::new(RwLock::new(Box::new(MemoryStorage::new())));
         let handler = Handler {
-            _phantom: marker::PhantomData,
             node_id: node_id.clone(),
+            address: address.clone(),
             table: table.clone(),
             data: data.clone(),
-            clean_needed: false,
+            validator: Arc::new(RwLock::new(None)),
+            value_ttl: Arc::new(RwLock::new(VALUE_TTL)),
+            received: Arc::new(RwLock::new(HashMap::new())),
+            clean_needed: Arc::new(AtomicBool::new(false)),
+            rng: Arc::new(Mutex::new(XorShiftRng::seed_from_u64(
+                rand::thread_rng().gen(),
+            ))),
+            epoch: (Instant::now(), SystemTime::now()),
         };
         Service {
-            handler: handler,
-            node_id: node_id,
-            table: table,
-            data: data,
+            handler,
+            node_id,
+            address,
+            table,
+            data,
+            persistence: None,
+            put_quorum: 1,
         }
     }
 
+    /// Get a handler for serving incoming requests.
+    pub fn handler(&self) -> Handler<TId, TAddr, TNodeTable, TData> {
+        self.handler.clone()
+    }
     /// Get an immutable reference to the node table.
-    pub fn node_table(&self) -> RwLockReadGuard<TNodeTable> {
+    pub fn node_table(&self) -> RwLockReadGuard<'_, TNodeTable> {
         self.table.read().unwrap()
     }
     /// Get a mutable reference to the node table.
-    pub fn node_table_mut(&mut self) -> RwLockWriteGuard<TNodeTable> {
+    pub fn node_table_mut(&mut self) -> RwLockWriteGuard<'_, TNodeTable> {
         self.table.write().unwrap()
     }
     /// Get the current node ID.
     pub fn node_id(&self) -> &TId {
         &self.node_id
     }
+    /// Get the current node address.
+    pub fn address(&self) -> &TAddr {
+        &self.address
+    }
     /// Get an immutable reference to the data.
-    pub fn stored_data(&self) -> RwLockReadGuard<HashMap<TId, TData>> {
+    pub fn stored_data(&self) -> RwLockReadGuard<'_, Box<dyn Storage<TId, TData>>> {
         self.data.read().unwrap()
     }
-    /// Get an immutable reference to the data.
-    pub fn stored_data_mut(&mut self) -> RwLockWriteGuard<HashMap<TId, TData>> {
+    /// Get a mutable reference to the data.
+    pub fn stored_data_mut(&mut self) -> RwLockWriteGuard<'_, Box<dyn Storage<TId, TData>>> {
         self.data.write().unwrap()
     }
+    /// Replace the storage for data, dropping the currently stored values.
+    pub fn set_storage<TStorage>(&mut self, storage: TStorage)
+    where
+        TStorage: Storage<TId, TData> + 'static,
+    {
+        *self.data.write().unwrap() = Box::new(storage);
+        self.handler.received.write().unwrap().clear();
+    }
+    /// Set a function validating values received from the network.
+    ///
+    /// Values for which `validator` returns false are not stored.
+    pub fn set_store_validator<TValidator>(&mut self, validator: TValidator)
+    where
+        TValidator: Fn(&TId, &TData) -> bool + Send + Sync + 'static,
+    {
+        *self.handler.validator.write().unwrap() = Some(Box::new(validator));
+    }
+    /// Set the number of nodes that must acknowledge a value for `put`.
+    ///
+    /// Defaults to 1.
+    pub fn set_put_quorum(&mut self, quorum: usize) {
+        self.put_quorum = quorum;
+    }
+    /// Set the time for which received values are stored.
+    ///
+    /// Requests asking to store values for longer are limited to this time.
+    /// Defaults to `VALUE_TTL`.
+    pub fn set_value_ttl(&mut self, ttl: Duration) {
+        *self.handler.value_ttl.write().unwrap() = ttl;
+    }
+    /// Remove values expired by `now`, returns their number.
+    ///
+    /// Expired values are never returned, but should be removed
+    /// periodically to free the storage.
+    pub fn remove_expired_values(&mut self, now: Instant) -> usize {
+        let mut data = self.data.write().unwrap();
+        let count = data.remove_expired(self.handler.system_time(now));
+        if count > 0 {
+            debug!("Removed {} expired values", count);
+            self.handler
+                .received
+                .write()
+                .unwrap()
+                .retain(|id, _| data.contains(id));
+        }
+        count
+    }
     /// Check if some buckets are full already.
     pub fn clean_needed(&self) -> bool {
-        self.handler.clean_needed
+        self.handler.clean_needed()
     }
 
-    /// Try to clean up the table by checking the oldest records.
+    /// Try to clean up the table by checking the oldest records at `now`.
     ///
     /// Should be called periodically, especially when clean_needed is true.
-    pub fn clean_up<TCheck>(&mut self, mut check: TCheck)
+    pub fn clean_up<TCheck>(&mut self, mut check: TCheck, now: Instant)
     where
         TCheck: FnMut(&Node<TId, TAddr>) -> bool,
     {
         {
             let mut node_table = self.node_table_mut();
-            let oldest = node_table.pop_oldest();
+            let oldest = node_table.pop_oldest(now);
             for node in oldest {
-                if check(&node) {
-                    node_table.update(&node);
+                if !check(&node) || !node_table.update_at(&node, now) {
+                    node_table.record_failure(&node.id);
+                }
+            }
+        }
+        self.handler.clean_needed.store(false, Ordering::SeqCst);
+    }
+}
+
+impl<TId, TAddr, TNodeTable, TData> Service<TId, TAddr, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TAddr: Clone + Send + Sync + 'static,
+    TNodeTable: GenericNodeTable<TId, TAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    /// Find nodes closest to the given ID in the network.
+    ///
+    /// Runs an iterative lookup starting with the closest known nodes and
+    /// querying up to `ALPHA` of them in parallel through `transport`.
+    /// Blocks until the closest found nodes have all answered.
+    pub fn lookup_node<TTransport>(
+        &mut self,
+        transport: &TTransport,
+        id: &TId,
+    ) -> Vec<Node<TId, TAddr>>
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        self.lookup_closest(transport, id, MAX_NODE_COUNT)
+    }
+
+    /// Find up to `count` nodes closest to the given ID in the network.
+    fn lookup_closest<TTransport>(
+        &mut self,
+        transport: &TTransport,
+        id: &TId,
+        count: usize,
+    ) -> Vec<Node<TId, TAddr>>
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        let mut lookup = self.new_lookup(id, count, transport.now());
+        self.run_lookup(
+            transport,
+            &mut lookup,
+            || RequestPayload::FindNode(id.clone()),
+            |payload| match payload {
+                ResponsePayload::NodesFound(nodes) => LookupStep::Continue(nodes),
+                _ => LookupStep::Fail,
+            },
+        );
+        lookup.closest()
+    }
+
+    /// Find a value in the network.
+    ///
+    /// Returns a locally stored value if any, otherwise runs an iterative
+    /// lookup until any node returns the value. The value is then cached on
+    /// the closest node seen that did not have it, see `cache_ttl` for how
+    /// long. Expiration of local values is checked using the clock of
+    /// `transport`.
+    pub fn get<TTransport>(&mut self, transport: &TTransport, id: &TId) -> Option<TData>
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        let now = transport.now();
+        let system_now = self.handler.system_time(now);
+        if let Some(value) = self.data.read().unwrap().get_alive(id, system_now) {
+            return Some(value);
+        }
+
+        let mut lookup = self.new_lookup(id, MAX_NODE_COUNT, now);
+        let mut result = None;
+        self.run_lookup(
+            transport,
+            &mut lookup,
+            || RequestPayload::FindValue(id.clone()),
+            |payload| match payload {
+                ResponsePayload::ValueFound(value) => {
+                    result = Some(value);
+                    LookupStep::Stop
+                }
+                ResponsePayload::NodesFound(nodes) => LookupStep::Continue(nodes),
+                _ => LookupStep::Fail,
+            },
+        );
+
+        if let Some(ref value) = result {
+            // Nodes that answered with other nodes did not have the value
+            if let Some(node) = lookup.closest().first() {
+                let ttl = self.cache_ttl(id, node);
+                debug!(
+                    "Caching value for {:?} on node {:?} for {:?}",
+                    id, node.id, ttl
+                );
+                let payload = RequestPayload::Store(id.clone(), value.clone(), Some(ttl));
+                transport.send(&node.address, self.new_request(payload), |_| ());
+            }
+        }
+        result
+    }
+
+    // TTL of a copy of the value cached on `node`. As suggested in section
+    // 2.3 of the Kademlia paper, it is halved for every known node closer to
+    // the value ID, starting with half of the value TTL.
+    fn cache_ttl(&self, id: &TId, node: &Node<TId, TAddr>) -> Duration {
+        let table = self.table.read().unwrap();
+        let distance = node.id.bitxor(id);
+        let closest = table.find(id, table.bucket_size());
+        let closer = closest.iter().filter(|n| n.id.bitxor(id) < distance);
+        let shift = cmp::min(closer.count() + 1, 31) as u32;
+        *self.handler.value_ttl.read().unwrap() / (1 << shift)
+    }
+
+    /// Store a value in the network.
+    ///
+    /// Runs a node lookup for `id` and sends the value to the k closest nodes
+    /// that answered, where k is the k-bucket size of the node table. Blocks until all of them answer or fail, returns the
+    /// number of nodes that acknowledged the value if it is not less than
+    /// the quorum (see `set_put_quorum`). The value is not stored locally.
+    pub fn put<TTransport>(
+        &mut self,
+        transport: &TTransport,
+        id: &TId,
+        value: TData,
+    ) -> Result<usize, PutError>
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        let count = self.table.read().unwrap().bucket_size();
+        let closest = self.lookup_closest(transport, id, count);
+        let (sender, receiver) = mpsc::channel();
+        for node in &closest {
+            let payload = RequestPayload::Store(id.clone(), value.clone(), None);
+            let response_sender = ResponseSender::new(&sender, node.id.clone(), transport);
+            transport.send(&node.address, self.new_request(payload), move |response| {
+                response_sender.send(response)
+            });
+        }
+
+        let mut acknowledged = 0;
+        for _ in &closest {
+            match receiver.recv().unwrap() {
+                (node_id, Some(response), rtt) => match response.payload {
+                    ResponsePayload::Error(code, ref message) => debug!(
+                        "Node {:?} rejected value for {:?}: {} {}",
+                        node_id, id, code, message
+                    ),
+                    _ => {
+                        self.record_response(&response, rtt, transport.now());
+                        acknowledged += 1;
+                    }
+                },
+                (node_id, None, _) => {
+                    debug!("Node {:?} failed to answer", node_id);
+                    self.table.write().unwrap().record_failure(&node_id);
+                }
+            }
+        }
+        info!(
+            "Value for {:?} stored on {} of {} nodes",
+            id,
+            acknowledged,
+            closest.len()
+        );
+        if acknowledged < self.put_quorum {
+            return Err(PutError::QuorumNotReached(acknowledged));
+        }
+        Ok(acknowledged)
+    }
+
+    /// Look up a random ID in every bucket idle for `REFRESH_INTERVAL`.
+    ///
+    /// Keeps sparse parts of the node table populated. Should be called
+    /// periodically, returns the number of refreshed buckets.
+    pub fn refresh_buckets<TTransport>(&mut self, transport: &TTransport, now: Instant) -> usize
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        let ids = {
+            let mut rng = self.handler.rng.lock().unwrap();
+            self.table
+                .read()
+                .unwrap()
+                .stale_buckets(now, REFRESH_INTERVAL, &mut *rng)
+        };
+        for id in &ids {
+            debug!("Refreshing bucket with random ID {:?}", id);
+            let _ = self.lookup_node(transport, id);
+            self.table.write().unwrap().record_lookup(id, now);
+        }
+        ids.len()
+    }
+
+    fn new_lookup(&self, id: &TId, count: usize, now: Instant) -> Lookup<TId, TAddr> {
+        let mut table = self.table.write().unwrap();
+        table.record_lookup(id, now);
+        let nodes = table.find(id, count);
+        Lookup::new(id.clone(), count, nodes)
+    }
+
+    /// Drive the lookup until it is finished or stopped.
+    ///
+    /// `payload` builds requests, `on_response` decides what to do with
+    /// every received response.
+    fn run_lookup<TTransport, TPayload, TOnResponse>(
+        &mut self,
+        transport: &TTransport,
+        lookup: &mut Lookup<TId, TAddr>,
+        payload: TPayload,
+        mut on_response: TOnResponse,
+    ) where
+        TTransport: Transport<TId, TAddr, TData>,
+        TPayload: Fn() -> RequestPayload<TId, TData>,
+        TOnResponse: FnMut(ResponsePayload<TId, TAddr, TData>) -> LookupStep<TId, TAddr>,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        loop {
+            while lookup.in_flight() < ALPHA {
+                let node = match lookup.next() {
+                    Some(node) => node,
+                    None => break,
+                };
+                let request = self.new_request(payload());
+                let address = node.address.clone();
+                let response_sender = ResponseSender::new(&sender, node, transport);
+                transport.send(&address, request, move |response| {
+                    response_sender.send(response)
+                });
+            }
+            if lookup.in_flight() == 0 || lookup.is_finished() {
+                break;
+            }
+
+            let (node, response, rtt) = receiver.recv().unwrap();
+            let response = match response {
+                Some(response) => response,
+                None => {
+                    debug!("Node {:?} failed to answer", node.id);
+                    self.table.write().unwrap().record_failure(&node.id);
+                    lookup.on_failure(&node.id);
+                    continue;
+                }
+            };
+
+            self.record_response(&response, rtt, transport.now());
+            match on_response(response.payload) {
+                LookupStep::Continue(nodes) => {
+                    let nodes = nodes.into_iter().filter(|n| n.id != self.node_id).collect();
+                    lookup.on_response(&node.id, nodes);
+                }
+                LookupStep::Fail => {
+                    debug!("Node {:?} returned unexpected response", node.id);
+                    lookup.on_failure(&node.id);
                 }
+                LookupStep::Stop => break,
+            }
+        }
+    }
+
+    /// Send stored values again to the nodes closest to them.
+    ///
+    /// Values received from other nodes or republished within
+    /// `REPUBLISH_INTERVAL` before `now` are skipped: the sender most likely
+    /// stored them on other close nodes as well (see section 2.5 of the
+    /// Kademlia paper). Copies cached after lookups of other nodes are never
+    /// republished. The rest is sent to the closest nodes found by a
+    /// lookup of their IDs (k of them, as in `put`), to be stored for the rest
+    /// of their lifetime.
+    /// Should be called periodically, returns the number of republished
+    /// values.
+    pub fn republish<TTransport>(&mut self, transport: &TTransport, now: Instant) -> usize
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        let values: Vec<_> = {
+            let data = self.data.read().unwrap();
+            let received = self.handler.received.read().unwrap();
+            let system_now = self.handler.system_time(now);
+            data.iter()
+                .filter(|(id, _)| {
+                    received
+                        .get(id)
+                        .is_none_or(|r| !r.cached && r.at + REPUBLISH_INTERVAL <= now)
+                })
+                .filter_map(|(id, _)| {
+                    let value = data.get_alive(&id, system_now)?;
+                    let ttl = data.expires(&id)?.duration_since(system_now).ok()?;
+                    Some((id, value, ttl))
+                })
+                .collect()
+        };
+        let count = self.table.read().unwrap().bucket_size();
+        for (id, value, ttl) in &values {
+            let closest = self.lookup_closest(transport, id, count);
+            debug!("Republishing value for {:?} to {} nodes", id, closest.len());
+            for node in closest {
+                let payload = RequestPayload::Store(id.clone(), value.clone(), Some(*ttl));
+                transport.send(&node.address, self.new_request(payload), |_| ());
+            }
+            let mut received = self.handler.received.write().unwrap();
+            let node_id = &self.node_id;
+            received
+                .entry(id.clone())
+                .or_insert_with(|| Received {
+                    at: now,
+                    publisher: node_id.clone(),
+                    cached: false,
+                })
+                .at = now;
+        }
+        values.len()
+    }
+
+    /// Join the network using the given seed nodes.
+    ///
+    /// Pings the seeds to learn their IDs, looks up our own ID and then
+    /// looks up a random ID in every bucket further than the closest
+    /// neighbour found. Returns the number of seeds that answered.
+    pub fn bootstrap<TTransport>(
+        &mut self,
+        transport: &TTransport,
+        seeds: &[TAddr],
+    ) -> Result<usize, BootstrapError>
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        let (sender, receiver) = mpsc::channel();
+        for seed in seeds {
+            let response_sender = ResponseSender::new(&sender, (), transport);
+            transport.send(
+                seed,
+                self.new_request(RequestPayload::Ping),
+                move |response| response_sender.send(response),
+            );
+        }
+        let mut answered = 0;
+        for _ in seeds {
+            if let ((), Some(response), rtt) = receiver.recv().unwrap() {
+                match response.payload {
+                    ResponsePayload::Error(code, ref message) => {
+                        debug!("Seed node rejected ping: {} {}", code, message)
+                    }
+                    _ => {
+                        self.record_response(&response, rtt, transport.now());
+                        answered += 1;
+                    }
+                }
+            }
+        }
+        info!("{} of {} seed nodes answered", answered, seeds.len());
+        if answered == 0 {
+            return Err(BootstrapError::NoSeedAnswered);
+        }
+
+        let node_id = self.node_id.clone();
+        let closest = self.lookup_node(transport, &node_id);
+        let hash_size = self.table.read().unwrap().hash_size();
+        let first = match closest.first() {
+            Some(node) => node.id.bitxor(&node_id).bits(),
+            None => hash_size,
+        };
+        info!(
+            "Found {} nodes close to us, refreshing {} buckets",
+            closest.len(),
+            hash_size.saturating_sub(first)
+        );
+        for index in first..hash_size {
+            let id = self.random_id_at_distance(index + 1);
+            let found = self.lookup_node(transport, &id);
+            debug!("Refreshed bucket {}, found {} nodes", index, found.len());
+        }
+        Ok(answered)
+    }
+
+    /// Ping the oldest nodes of full k-buckets, see `Handler::ping_oldest`.
+    pub fn ping_oldest<TTransport>(&mut self, transport: &TTransport, now: Instant)
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        self.handler.ping_oldest(transport, now);
+    }
+
+    fn new_request(&self, payload: RequestPayload<TId, TData>) -> Request<TId, TAddr, TData> {
+        self.handler.new_request(payload)
+    }
+
+    /// Remember the node that answered within `rtt` at time `now`.
+    ///
+    /// Error responses may not identify the responder and are ignored.
+    fn record_response(
+        &mut self,
+        response: &Response<TId, TAddr, TData>,
+        rtt: Duration,
+        now: Instant,
+    ) {
+        if let ResponsePayload::Error(..) = response.payload {
+            return;
+        }
+        if let Some(ref responder) = response.responder {
+            if self.handler.update(responder, now).is_ok() {
+                self.table
+                    .write()
+                    .unwrap()
+                    .record_response(&responder.id, rtt, now);
             }
         }
-        self.handler.clean_needed = false;
+    }
+
+    /// Random ID with distance to ours having exactly `bits` bits.
+    fn random_id_at_distance(&self, bits: usize) -> TId {
+        let random = self.handler.random_id();
+        self.node_id.diverge_at(bits - 1, &random)
+    }
+}
+
+impl<TId, TData> Service<TId, net::SocketAddr, KNodeTable<TId, net::SocketAddr>, TData>
+where
+    TId: GenericId + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    /// Create a service with the node table saved in a file.
+    ///
+    /// The table is loaded from `path` if it exists, otherwise `node_table`
+    /// is used. The ID of the service is the one of the table. A loaded table
+    /// gets the settings of `node_table` and must have the same hash and
+    /// k-bucket sizes. Use `save_node_table_if_needed` to save the table
+    /// every `interval`.
+    pub fn new_persistent<P: AsRef<Path>>(
+        node_table: KNodeTable<TId, net::SocketAddr>,
+        address: net::SocketAddr,
+        path: P,
+        interval: Duration,
+    ) -> io::Result<Service<TId, net::SocketAddr, KNodeTable<TId, net::SocketAddr>, TData>> {
+        let path = path.as_ref().to_path_buf();
+        let node_table = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let mut loaded: KNodeTable<TId, net::SocketAddr> = json::decode(&contents)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                loaded
+                    .apply_settings(&node_table)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                loaded
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!(
+                    "Node table file {:?} not found, starting from scratch",
+                    path
+                );
+                node_table
+            }
+            Err(e) => return Err(e),
+        };
+        let node_id = node_table.this_id().clone();
+        let mut service = Service::new_with_id(node_table, node_id, address);
+        service.persistence = Some(Persistence {
+            path,
+            interval,
+            last_saved: Instant::now(),
+        });
+        Ok(service)
+    }
+
+    /// Save the node table to the file given to `new_persistent`.
+    ///
+    /// Does nothing for services created without a file.
+    pub fn save_node_table(&mut self) -> io::Result<()> {
+        let path = match self.persistence {
+            Some(ref persistence) => persistence.path.clone(),
+            None => return Ok(()),
+        };
+        let contents = json::encode(&*self.table.read().unwrap())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        // Write to a temporary file first to never leave a broken one
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, &path)?;
+        debug!("Saved node table to {:?}", path);
+        Ok(())
+    }
+
+    /// Save the node table if the save interval has passed since `now`.
+    ///
+    /// Returns whether the table was saved. Should be called periodically.
+    pub fn save_node_table_if_needed(&mut self, now: Instant) -> io::Result<bool> {
+        match self.persistence {
+            Some(ref persistence) if now >= persistence.last_saved + persistence.interval => (),
+            _ => return Ok(false),
+        }
+        self.save_node_table()?;
+        if let Some(ref mut persistence) = self.persistence {
+            persistence.last_saved = now;
+        }
+        Ok(true)
+    }
+}
+
+impl<TId, TAddr, TNodeTable, TData> Clone for Handler<TId, TAddr, TNodeTable, TData>
+where
+    TId: GenericId,
+    TAddr: Clone,
+    TNodeTable: GenericNodeTable<TId, TAddr>,
+    TData: Send + Sync + Clone + PartialEq,
+{
+    fn clone(&self) -> Handler<TId, TAddr, TNodeTable, TData> {
+        Handler {
+            node_id: self.node_id.clone(),
+            address: self.address.clone(),
+            table: self.table.clone(),
+            data: self.data.clone(),
+            validator: self.validator.clone(),
+            value_ttl: self.value_ttl.clone(),
+            received: self.received.clone(),
+            clean_needed: self.clean_needed.clone(),
+            rng: self.rng.clone(),
+            epoch: self.epoch,
+        }
     }
 }
 
@@ -140,56 +883,365 @@ impl<TId, TAddr, TNodeTable, TData> Handler<TId, TAddr, TNodeTable, TData>
 where
     TId: GenericId,
     TNodeTable: GenericNodeTable<TId, TAddr>,
-    TData: Send + Sync + Clone,
+    TData: Send + Sync + Clone + PartialEq,
 {
+    /// Get the current node ID.
+    pub fn node_id(&self) -> &TId {
+        &self.node_id
+    }
+    /// Get the current node address.
+    pub fn address(&self) -> &TAddr {
+        &self.address
+    }
+    /// Reseed the generator of request IDs, e.g. for reproducible
+    /// simulations.
+    pub fn seed_rng(&self, seed: u64) {
+        *self.rng.lock().unwrap() = XorShiftRng::seed_from_u64(seed);
+    }
+    /// Process a request received at time `now` and build a response to it.
+    ///
+    /// Dispatches the request to the corresponding `on_*` method.
+    pub fn handle(
+        &mut self,
+        request: Request<TId, TAddr, TData>,
+        now: Instant,
+    ) -> Response<TId, TAddr, TData>
+    where
+        TAddr: Clone,
+    {
+        let payload = match request.payload {
+            RequestPayload::Ping => {
+                self.on_ping(&request.caller, now);
+                ResponsePayload::NoResult
+            }
+            RequestPayload::FindNode(ref id) => {
+                ResponsePayload::NodesFound(self.on_find_node(&request.caller, id, now))
+            }
+            RequestPayload::FindValue(ref id) => {
+                match self.on_find_value(&request.caller, id, now) {
+                    FindResult::Value(value) => ResponsePayload::ValueFound(value),
+                    FindResult::ClosestNodes(nodes) => ResponsePayload::NodesFound(nodes),
+                    FindResult::Nothing => ResponsePayload::NoResult,
+                }
+            }
+            RequestPayload::Store(ref id, ref value, ttl) => {
+                if self.on_store(&request.caller, id, value.clone(), ttl, now) {
+                    ResponsePayload::NoResult
+                } else {
+                    ResponsePayload::Error(ERROR_GENERIC, "Value rejected".to_string())
+                }
+            }
+        };
+        Response {
+            request,
+            responder: Some(Node {
+                id: self.node_id.clone(),
+                address: self.address.clone(),
+            }),
+            payload,
+        }
+    }
     /// Process the ping request.
     ///
     /// Essentially remembers the incoming node and returns true.
-    pub fn on_ping(&mut self, sender: &Node<TId, TAddr>) -> bool {
-        self.update(sender);
+    pub fn on_ping(&mut self, sender: &Node<TId, TAddr>, now: Instant) -> bool {
+        let _ = self.update(sender, now);
         true
     }
     /// Process the find request.
-    pub fn on_find_node(&mut self, sender: &Node<TId, TAddr>, id: &TId) -> Vec<Node<TId, TAddr>> {
-        let res = self.table.read().unwrap().find(&id, MAX_NODE_COUNT);
-        self.update(sender);
+    pub fn on_find_node(
+        &mut self,
+        sender: &Node<TId, TAddr>,
+        id: &TId,
+        now: Instant,
+    ) -> Vec<Node<TId, TAddr>> {
+        let res = self.table.read().unwrap().find(id, MAX_NODE_COUNT);
+        let _ = self.update(sender, now);
         res
     }
-    /// Find a value or the closes nodes.
+    /// Find a value not expired by `now` or the closes nodes.
     pub fn on_find_value(
         &mut self,
         sender: &Node<TId, TAddr>,
         id: &TId,
+        now: Instant,
     ) -> FindResult<TId, TAddr, TData> {
-        self.update(sender);
-        let data = self.data.read().unwrap();
-        let table = self.table.read().unwrap();
-        let res = match data.get(&id) {
-            Some(value) => FindResult::Value(value.clone()),
-            None => FindResult::ClosestNodes(table.find(&id, MAX_NODE_COUNT)),
+        let _ = self.update(sender, now);
+        let value = self
+            .data
+            .read()
+            .unwrap()
+            .get_alive(id, self.system_time(now));
+        match value {
+            Some(value) => FindResult::Value(value),
+            None => FindResult::ClosestNodes(self.table.read().unwrap().find(id, MAX_NODE_COUNT)),
+        }
+    }
+    /// Process the store request.
+    ///
+    /// The value is stored for `ttl` since `now`, but no longer than the
+    /// value TTL of the service, see `Storage::store` for how it is combined
+    /// with a stored one: only the node that stored it first may renew a
+    /// different value. Values are considered cached copies if k closer
+    /// nodes are known, those are not republished. Returns whether the value
+    /// was accepted.
+    pub fn on_store(
+        &mut self,
+        sender: &Node<TId, TAddr>,
+        id: &TId,
+        value: TData,
+        ttl: Option<Duration>,
+        now: Instant,
+    ) -> bool {
+        let _ = self.update(sender, now);
+        if let Some(ref validator) = *self.validator.read().unwrap() {
+            if !validator(id, &value) {
+                debug!("Rejected value for {:?} from {:?}", id, sender.id);
+                return false;
+            }
+        }
+        let max_ttl = *self.value_ttl.read().unwrap();
+        let ttl = ttl.map_or(max_ttl, |ttl| cmp::min(ttl, max_ttl));
+        let system_now = self.system_time(now);
+        let cached = {
+            let table = self.table.read().unwrap();
+            let distance = self.node_id.bitxor(id);
+            let closest = table.find(id, table.bucket_size());
+            let closer = closest.iter().filter(|n| n.id.bitxor(id) < distance);
+            closer.count() >= table.bucket_size()
         };
-        res
+
+        let mut data = self.data.write().unwrap();
+        let mut received = self.received.write().unwrap();
+        let current = data.get_alive(id, system_now);
+        // Values without a record were stored locally, i.e. published by us
+        let publisher = match (&current, received.get(id)) {
+            (None, _) => sender.id.clone(),
+            (Some(_), Some(r)) => r.publisher.clone(),
+            (Some(_), None) => self.node_id.clone(),
+        };
+        let renew = publisher == sender.id || current.as_ref() == Some(&value);
+        if !data.store(id.clone(), value, system_now + ttl, system_now, renew) {
+            return false;
+        }
+        let _ = received.insert(
+            id.clone(),
+            Received {
+                at: now,
+                publisher,
+                cached,
+            },
+        );
+        true
     }
 
-    fn update(&mut self, node: &Node<TId, TAddr>) {
+    /// Check if some buckets are full already.
+    pub fn clean_needed(&self) -> bool {
+        self.clean_needed.load(Ordering::SeqCst)
+    }
+
+    // Only full k-buckets need a clean up, not ones over subnet limits
+    fn update(&mut self, node: &Node<TId, TAddr>, now: Instant) -> Result<(), UpdateError> {
         if node.id == self.node_id {
+            return Ok(());
+        }
+
+        let result = self.table.write().unwrap().try_update_at(node, now);
+        if result == Err(UpdateError::BucketFull) {
+            self.clean_needed.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+
+    fn new_request(&self, payload: RequestPayload<TId, TData>) -> Request<TId, TAddr, TData>
+    where
+        TAddr: Clone,
+    {
+        Request {
+            caller: Node {
+                id: self.node_id.clone(),
+                address: self.address.clone(),
+            },
+            request_id: self.random_id(),
+            payload,
+        }
+    }
+
+    // System time of `now` for expiration of values, which may outlive us
+    fn system_time(&self, now: Instant) -> SystemTime {
+        let (instant, system) = self.epoch;
+        if now >= instant {
+            system + (now - instant)
+        } else {
+            system - (instant - now)
+        }
+    }
+
+    fn random_id(&self) -> TId {
+        let hash_size = self.table.read().unwrap().hash_size();
+        TId::gen_with(hash_size, &mut *self.rng.lock().unwrap())
+    }
+}
+
+impl<TId, TAddr, TNodeTable, TData> Handler<TId, TAddr, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TAddr: Clone + Send + Sync + 'static,
+    TNodeTable: GenericNodeTable<TId, TAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    /// Ping the oldest nodes of full k-buckets if some newcomers did not fit.
+    ///
+    /// The nodes are popped from the table at `now`, so that newcomers can
+    /// take their place (see `KNodeTable`), and pinged without waiting for
+    /// responses. Nodes that answer are put back as seen at `now`, failures
+    /// of the others are recorded. Transports serving requests call this
+    /// automatically.
+    pub fn ping_oldest<TTransport>(&self, transport: &TTransport, now: Instant)
+    where
+        TTransport: Transport<TId, TAddr, TData>,
+    {
+        if !self.clean_needed.swap(false, Ordering::SeqCst) {
             return;
         }
 
-        if !self.table.write().unwrap().update(&node) {
-            self.clean_needed = true;
+        let oldest = self.table.write().unwrap().pop_oldest(now);
+        for node in oldest {
+            debug!("Checking whether node {:?} is still alive", node.id);
+            let request = self.new_request(RequestPayload::Ping);
+            let table = self.table.clone();
+            let address = node.address.clone();
+            let sent = transport.now();
+            let clock = transport.clock();
+            transport.send(&address, request, move |response| match response {
+                Some(Response {
+                    payload: ResponsePayload::Error(..),
+                    ..
+                })
+                | None => {
+                    debug!("Evicting node {:?} which failed to answer", node.id);
+                    table.write().unwrap().record_failure(&node.id);
+                }
+                Some(..) => {
+                    let received = clock();
+                    let mut table = table.write().unwrap();
+                    if table.update_at(&node, received) {
+                        let rtt = received.saturating_duration_since(sent);
+                        table.record_response(&node.id, rtt, received);
+                    } else {
+                        table.record_failure(&node.id);
+                    }
+                }
+            });
         }
     }
 }
 
 #[cfg(test)]
 pub mod test {
+    use super::super::protocol::{Request, RequestPayload, Response, ResponsePayload};
+    use super::super::transport::Transport;
     use super::super::utils::test;
-    use super::super::{GenericNodeTable, Node};
+    use super::super::{GenericNodeTable, KNodeTable, Node, SubnetLimits};
+    use std::collections::{HashMap, HashSet};
+    use std::env;
+    use std::fs;
+    use std::io;
     use std::net;
+    use std::process;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant, SystemTime};
     type TestsIdType = test::IdType;
 
-    use super::{FindResult, Service};
+    use super::super::protocol::ERROR_GENERIC;
+    use super::super::storage::{ListStorage, MemoryStorage, Storage};
+    use super::{FindResult, PutError, Service, UpdateError, REPUBLISH_INTERVAL, VALUE_TTL};
+
+    /// Transport emulating a network of nodes, each knowing some others.
+    struct DummyTransport {
+        nodes: HashMap<net::SocketAddr, (TestsIdType, Vec<Node<TestsIdType, net::SocketAddr>>)>,
+        values: HashMap<net::SocketAddr, String>,
+        stored: Mutex<Vec<(net::SocketAddr, TestsIdType, String)>>,
+        stored_ttls: Mutex<Vec<Option<Duration>>>,
+        // Nodes for which callbacks are dropped without being called
+        dropped: HashSet<net::SocketAddr>,
+        // Nodes answering every request with an error
+        failing: HashSet<net::SocketAddr>,
+    }
+
+    impl DummyTransport {
+        fn new() -> DummyTransport {
+            DummyTransport {
+                nodes: HashMap::new(),
+                values: HashMap::new(),
+                stored: Mutex::new(Vec::new()),
+                stored_ttls: Mutex::new(Vec::new()),
+                dropped: HashSet::new(),
+                failing: HashSet::new(),
+            }
+        }
+
+        fn add(&mut self, id: u8, known: &[u8]) {
+            let known = known.iter().map(|i| new_node(*i)).collect();
+            self.nodes
+                .insert(new_node(id).address, (test::make_id(id), known));
+        }
+    }
+
+    impl Transport<TestsIdType, net::SocketAddr, String> for DummyTransport {
+        fn send<F>(
+            &self,
+            address: &net::SocketAddr,
+            request: Request<TestsIdType, net::SocketAddr, String>,
+            callback: F,
+        ) where
+            F: FnOnce(Option<Response<TestsIdType, net::SocketAddr, String>>) + Send + 'static,
+        {
+            if self.dropped.contains(address) {
+                return;
+            }
+            let (id, known) = match self.nodes.get(address) {
+                Some(node) => node.clone(),
+                None => return callback(None),
+            };
+            let payload = match request.payload {
+                RequestPayload::FindNode(..) => ResponsePayload::NodesFound(known),
+                RequestPayload::FindValue(..) => match self.values.get(address) {
+                    Some(value) => ResponsePayload::ValueFound(value.clone()),
+                    None => ResponsePayload::NodesFound(known),
+                },
+                RequestPayload::Store(ref id, ref value, ttl) => {
+                    self.stored
+                        .lock()
+                        .unwrap()
+                        .push((*address, id.clone(), value.clone()));
+                    self.stored_ttls.lock().unwrap().push(ttl);
+                    ResponsePayload::NoResult
+                }
+                RequestPayload::Ping => ResponsePayload::NoResult,
+            };
+            if self.failing.contains(address) {
+                // Like KRPC errors, which do not identify the responder
+                return callback(Some(Response {
+                    request,
+                    responder: None,
+                    payload: ResponsePayload::Error(ERROR_GENERIC, "failed".to_string()),
+                }));
+            }
+            callback(Some(Response {
+                request,
+                responder: Some(Node {
+                    id,
+                    address: *address,
+                }),
+                payload,
+            }))
+        }
+    }
+
+    fn new_node(id: u8) -> Node<TestsIdType, net::SocketAddr> {
+        test::new_node_with_port(test::make_id(id), 9000 + id as u16)
+    }
 
     struct DummyNodeTable {
         pub node: Option<Node<TestsIdType, net::SocketAddr>>,
@@ -200,6 +1252,14 @@ pub mod test {
             test::make_id(42)
         }
 
+        fn hash_size(&self) -> usize {
+            8
+        }
+
+        fn bucket_size(&self) -> usize {
+            1
+        }
+
         fn update(&mut self, node: &Node<TestsIdType, net::SocketAddr>) -> bool {
             match self.node {
                 Some(..) => false,
@@ -222,7 +1282,7 @@ pub mod test {
             }
         }
 
-        fn pop_oldest(&mut self) -> Vec<Node<TestsIdType, net::SocketAddr>> {
+        fn pop_oldest(&mut self, _now: Instant) -> Vec<Node<TestsIdType, net::SocketAddr>> {
             let result;
             if let Some(ref node) = self.node {
                 result = vec![node.clone()];
@@ -232,13 +1292,31 @@ pub mod test {
             self.node = None;
             result
         }
+
+        fn remove(&mut self, id: &TestsIdType) -> Option<Node<TestsIdType, net::SocketAddr>> {
+            match self.node {
+                Some(ref node) if node.id == *id => (),
+                _ => return None,
+            }
+            self.node.take()
+        }
+
+        fn get(&self, id: &TestsIdType) -> Option<&Node<TestsIdType, net::SocketAddr>> {
+            self.node.as_ref().filter(|node| node.id == *id)
+        }
+
+        fn iter<'a>(
+            &'a self,
+        ) -> Box<dyn Iterator<Item = &'a Node<TestsIdType, net::SocketAddr>> + 'a> {
+            Box::new(self.node.iter())
+        }
     }
 
     #[test]
     fn test_new() {
         let node_table = DummyNodeTable { node: None };
         let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
-            Service::new(node_table);
+            Service::new(node_table, test::make_addr(8008));
 
         assert_eq!(test::make_id(42), *svc.node_id());
         assert!(svc.node_table().node.is_none());
@@ -250,79 +1328,123 @@ pub mod test {
     fn test_find_saves_node() {
         let node_table = DummyNodeTable { node: None };
         let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
-            Service::new(node_table);
+            Service::new(node_table, test::make_addr(8008));
         let node = test::new_node(test::make_id(43));
 
-        assert!(svc.handler.on_find_node(&node, &node.id).is_empty());
-        let result = svc.handler.on_find_node(&node, &node.id);
+        assert!(svc
+            .handler
+            .on_find_node(&node, &node.id, Instant::now())
+            .is_empty());
+        let result = svc.handler.on_find_node(&node, &node.id, Instant::now());
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id)
+        assert_eq!(test::make_id(43), result.first().unwrap().id)
+    }
+
+    #[test]
+    fn test_subnet_limit_no_clean() {
+        let mut node_table = KNodeTable::new_with_details(test::make_id(0), 1, 8);
+        node_table.set_subnet_limits(SubnetLimits {
+            per_bucket: None,
+            per_table: Some(1),
+        });
+        let mut svc: Service<TestsIdType, net::SocketAddr, _, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        let now = Instant::now();
+
+        assert!(svc.handler.on_ping(&test::new_node(test::make_id(2)), now));
+        let node = test::new_node_with_port(test::make_id(3), 8009);
+        assert_eq!(
+            Err(UpdateError::TableSubnetLimit),
+            svc.handler.update(&node, now)
+        );
+        assert!(!svc.clean_needed());
+
+        let node = Node {
+            id: test::make_id(3),
+            address: "10.0.0.1:8008".parse().unwrap(),
+        };
+        assert_eq!(Err(UpdateError::BucketFull), svc.handler.update(&node, now));
+        assert!(svc.clean_needed());
     }
 
     #[test]
     fn test_ping_find_clean() {
         let node_table = DummyNodeTable { node: None };
         let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
-            Service::new(node_table);
+            Service::new(node_table, test::make_addr(8008));
         let node = test::new_node(test::make_id(43));
 
-        assert!(svc.handler.on_ping(&node));
+        assert!(svc.handler.on_ping(&node, Instant::now()));
         assert_eq!(
             test::make_id(43),
             svc.node_table().node.as_ref().unwrap().id
         );
         assert!(!svc.clean_needed());
 
-        assert!(svc.handler.on_ping(&test::new_node(test::make_id(44))));
+        assert!(svc
+            .handler
+            .on_ping(&test::new_node(test::make_id(44)), Instant::now()));
         assert_eq!(
             test::make_id(43),
             svc.node_table().node.as_ref().unwrap().id
         );
         assert!(svc.clean_needed());
 
-        let mut result = svc.handler.on_find_node(&node, &node.id);
+        let mut result = svc.handler.on_find_node(&node, &node.id, Instant::now());
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id);
+        assert_eq!(test::make_id(43), result.first().unwrap().id);
 
         let mut flag = false;
-        svc.clean_up(|node| {
-            assert_eq!(test::make_id(43), node.id);
-            flag = true;
-            true
-        });
+        svc.clean_up(
+            |node| {
+                assert_eq!(test::make_id(43), node.id);
+                flag = true;
+                true
+            },
+            Instant::now(),
+        );
         assert!(flag);
         assert!(!svc.clean_needed());
 
-        result = svc.handler.on_find_node(&node, &node.id);
+        result = svc.handler.on_find_node(&node, &node.id, Instant::now());
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id);
+        assert_eq!(test::make_id(43), result.first().unwrap().id);
 
         flag = false;
-        svc.clean_up(|node| {
-            assert_eq!(test::make_id(43), node.id);
-            flag = true;
-            false
-        });
+        svc.clean_up(
+            |node| {
+                assert_eq!(test::make_id(43), node.id);
+                flag = true;
+                false
+            },
+            Instant::now(),
+        );
         assert!(flag);
         assert!(!svc.clean_needed());
-        assert!(svc.handler.on_find_node(&node, &node.id).is_empty());
+        assert!(svc
+            .handler
+            .on_find_node(&node, &node.id, Instant::now())
+            .is_empty());
     }
 
     #[test]
     fn test_ping_find_value() {
         let node_table = DummyNodeTable { node: None };
         let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
-            Service::new(node_table);
+            Service::new(node_table, test::make_addr(8008));
         let node = test::new_node(test::make_id(43));
         let id1: TestsIdType = test::make_id(44);
         let id2: TestsIdType = test::make_id(43);
 
-        svc.handler.on_ping(&node);
-        svc.stored_data_mut()
-            .insert(id1.clone(), "foobar".to_string());
+        svc.handler.on_ping(&node, Instant::now());
+        svc.stored_data_mut().put(
+            id1.clone(),
+            "foobar".to_string(),
+            SystemTime::now() + VALUE_TTL,
+        );
 
         {
-            let res1 = svc.handler.on_find_value(&node, &id1);
+            let res1 = svc.handler.on_find_value(&node, &id1, Instant::now());
             match res1 {
                 FindResult::Value(value) => assert_eq!("foobar", value),
                 _ => panic!("wrong result {:?}", res1),
@@ -330,11 +1452,670 @@ pub mod test {
         }
 
         {
-            let res2 = svc.handler.on_find_value(&node, &id2);
+            let res2 = svc.handler.on_find_value(&node, &id2, Instant::now());
             match res2 {
                 FindResult::ClosestNodes(nodes) => assert_eq!(1, nodes.len()),
                 _ => panic!("wrong result {:?}", res2),
             }
         }
     }
+
+    #[test]
+    fn test_lookup_node() {
+        let node_table = KNodeTable::new(test::make_id(0));
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        svc.node_table_mut().update(&new_node(1));
+
+        let mut transport = DummyTransport::new();
+        transport.add(1, &[0, 2, 3]);
+        transport.add(2, &[1, 7]);
+        transport.add(3, &[6]);
+        transport.add(6, &[3]);
+        // Node 7 is known, but does not answer
+
+        let result = svc.lookup_node(&transport, &test::make_id(6));
+        let ids: Vec<_> = result.iter().map(|n| n.id.clone()).collect();
+        // 6 xor 6 = 0, 2 xor 6 = 4, 3 xor 6 = 5, 1 xor 6 = 7
+        assert_eq!(
+            vec![
+                test::make_id(6),
+                test::make_id(2),
+                test::make_id(3),
+                test::make_id(1)
+            ],
+            ids
+        );
+        // All responders are remembered
+        assert_eq!(4, svc.node_table().find(&test::make_id(6), 16).len());
+    }
+
+    #[test]
+    fn test_dropped_callbacks() {
+        let node_table = KNodeTable::new(test::make_id(0));
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        svc.node_table_mut().update(&new_node(1));
+        let mut transport = DummyTransport::new();
+        transport.add(1, &[0, 2]);
+        transport.add(2, &[1]);
+        let _ = transport.dropped.insert(new_node(2).address);
+
+        let result = svc.lookup_node(&transport, &test::make_id(2));
+        let ids: Vec<_> = result.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(vec![test::make_id(1)], ids);
+        svc.set_put_quorum(2);
+        assert_eq!(
+            Err(PutError::QuorumNotReached(1)),
+            svc.put(&transport, &test::make_id(2), "foo".to_string())
+        );
+        assert!(svc.bootstrap(&transport, &[new_node(2).address]).is_err());
+    }
+
+    #[test]
+    fn test_error_responses() {
+        let node_table = KNodeTable::new(test::make_id(0));
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        svc.node_table_mut().update(&new_node(1));
+        let mut transport = DummyTransport::new();
+        transport.add(1, &[0, 2]);
+        transport.add(2, &[1]);
+        let _ = transport.failing.insert(new_node(2).address);
+
+        let result = svc.lookup_node(&transport, &test::make_id(2));
+        let ids: Vec<_> = result.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(vec![test::make_id(1)], ids);
+        assert_eq!(
+            Ok(1),
+            svc.put(&transport, &test::make_id(2), "foo".to_string())
+        );
+        assert!(svc.bootstrap(&transport, &[new_node(2).address]).is_err());
+        // Nodes answering with errors are not added to the table
+        assert!(!svc.node_table().contains(&test::make_id(2)));
+    }
+
+    #[test]
+    fn test_republish() {
+        let node_table = KNodeTable::new(test::make_id(0));
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        svc.node_table_mut().update(&new_node(1));
+        let mut transport = DummyTransport::new();
+        transport.add(1, &[0, 2]);
+        transport.add(2, &[1]);
+
+        let now = Instant::now();
+        svc.stored_data_mut().put(
+            test::make_id(5),
+            "foo".to_string(),
+            SystemTime::now() + VALUE_TTL,
+        );
+        svc.stored_data_mut().put(
+            test::make_id(7),
+            "old".to_string(),
+            SystemTime::now() - Duration::from_secs(1),
+        );
+        // Received from another node, not republished
+        let mut handler = svc.handler();
+        assert!(handler.on_store(
+            &new_node(2),
+            &test::make_id(6),
+            "bar".to_string(),
+            None,
+            Instant::now()
+        ));
+
+        assert_eq!(1, svc.republish(&transport, now));
+        let mut stored = transport.stored.lock().unwrap().clone();
+        stored.sort();
+        assert_eq!(
+            vec![
+                (new_node(1).address, test::make_id(5), "foo".to_string()),
+                (new_node(2).address, test::make_id(5), "foo".to_string()),
+            ],
+            stored
+        );
+        // Republished recently
+        assert_eq!(0, svc.republish(&transport, now));
+
+        let later = Instant::now() + REPUBLISH_INTERVAL;
+        assert_eq!(2, svc.republish(&transport, later));
+        assert_eq!(6, transport.stored.lock().unwrap().len());
+
+        // Values put into a new storage are ours, even if they were
+        // received from another node before
+        svc.set_storage(MemoryStorage::new());
+        svc.stored_data_mut().put(
+            test::make_id(6),
+            "baz".to_string(),
+            SystemTime::now() + VALUE_TTL,
+        );
+        assert_eq!(1, svc.republish(&transport, now));
+    }
+
+    #[test]
+    fn test_put() {
+        let node_table = KNodeTable::new(test::make_id(0));
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        svc.node_table_mut().update(&new_node(1));
+        let mut transport = DummyTransport::new();
+        transport.add(1, &[0, 2, 3]);
+        transport.add(2, &[1]);
+        transport.add(3, &[1]);
+
+        svc.set_put_quorum(3);
+        assert_eq!(
+            Ok(3),
+            svc.put(&transport, &test::make_id(2), "foo".to_string())
+        );
+        let mut stored: Vec<_> = transport
+            .stored
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|&(address, _, _)| address)
+            .collect();
+        stored.sort();
+        assert_eq!(
+            vec![
+                new_node(1).address,
+                new_node(2).address,
+                new_node(3).address
+            ],
+            stored
+        );
+        assert!(svc.stored_data().is_empty());
+
+        svc.set_put_quorum(4);
+        assert_eq!(
+            Err(PutError::QuorumNotReached(3)),
+            svc.put(&transport, &test::make_id(2), "foo".to_string())
+        );
+    }
+
+    #[test]
+    fn test_put_bucket_size() {
+        let node_table = KNodeTable::new_with_details(test::make_id(0), 2, 8);
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        svc.node_table_mut().update(&new_node(1));
+        let mut transport = DummyTransport::new();
+        transport.add(1, &[0, 2, 3]);
+        transport.add(2, &[1]);
+        transport.add(3, &[1]);
+
+        // Stored on the k closest nodes only
+        assert_eq!(
+            Ok(2),
+            svc.put(&transport, &test::make_id(2), "foo".to_string())
+        );
+        let mut stored: Vec<_> = transport
+            .stored
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|&(address, _, _)| address)
+            .collect();
+        stored.sort();
+        assert_eq!(vec![new_node(2).address, new_node(3).address], stored);
+    }
+
+    #[test]
+    fn test_lookup_node_empty_table() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        let transport = DummyTransport::new();
+        assert!(svc.lookup_node(&transport, &test::make_id(6)).is_empty());
+    }
+
+    #[test]
+    fn test_get_caches_value() {
+        let node_table = KNodeTable::new(test::make_id(0));
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        svc.node_table_mut().update(&new_node(1));
+
+        let mut transport = DummyTransport::new();
+        transport.add(1, &[2, 3]);
+        transport.add(2, &[1]);
+        transport.add(3, &[6]);
+        transport.add(6, &[]);
+        transport
+            .values
+            .insert(new_node(6).address, "foobar".to_string());
+
+        let result = svc.get(&transport, &test::make_id(7));
+        assert_eq!(Some("foobar".to_string()), result);
+        // 3 xor 7 = 4 is the closest node without the value
+        assert_eq!(
+            vec![(new_node(3).address, test::make_id(7), "foobar".to_string())],
+            *transport.stored.lock().unwrap()
+        );
+        // Only node 6 is known to be closer
+        assert_eq!(
+            vec![Some(VALUE_TTL / 4)],
+            *transport.stored_ttls.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cached_not_republished() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        let transport = DummyTransport::new();
+        let node = test::new_node(test::make_id(43));
+        let now = Instant::now();
+
+        // The sender is closer to the ID than us and fills the k-bucket
+        assert!(svc
+            .handler
+            .on_store(&node, &test::make_id(43), "foo".to_string(), None, now));
+        assert!(svc
+            .handler
+            .on_store(&node, &test::make_id(44), "bar".to_string(), None, now));
+        assert_eq!(1, svc.republish(&transport, now + REPUBLISH_INTERVAL));
+    }
+
+    #[test]
+    fn test_get_not_found() {
+        let node_table = KNodeTable::new(test::make_id(0));
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(node_table, test::make_id(0), test::make_addr(8008));
+        svc.node_table_mut().update(&new_node(1));
+
+        let mut transport = DummyTransport::new();
+        transport.add(1, &[2]);
+        transport.add(2, &[1]);
+
+        assert!(svc.get(&transport, &test::make_id(7)).is_none());
+        assert!(transport.stored.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_local() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        svc.stored_data_mut().put(
+            test::make_id(7),
+            "foobar".to_string(),
+            SystemTime::now() + VALUE_TTL,
+        );
+        let transport = DummyTransport::new();
+        assert_eq!(
+            Some("foobar".to_string()),
+            svc.get(&transport, &test::make_id(7))
+        );
+    }
+
+    #[test]
+    fn test_store() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        let node = test::new_node(test::make_id(43));
+        let id: TestsIdType = test::make_id(44);
+
+        assert!(svc
+            .handler
+            .on_store(&node, &id, "foobar".to_string(), None, Instant::now()));
+        assert_eq!(
+            test::make_id(43),
+            svc.node_table().node.as_ref().unwrap().id
+        );
+        assert_eq!("foobar", svc.stored_data().get(&id).unwrap());
+
+        match svc.handler.on_find_value(&node, &id, Instant::now()) {
+            FindResult::Value(value) => assert_eq!("foobar", value),
+            res => panic!("wrong result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_store_rejected() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        svc.set_store_validator(|_id, value: &String| !value.is_empty());
+        let node = test::new_node(test::make_id(43));
+        let id: TestsIdType = test::make_id(44);
+
+        assert!(!svc
+            .handler
+            .on_store(&node, &id, "".to_string(), None, Instant::now()));
+        assert!(svc.stored_data().is_empty());
+        assert!(svc
+            .handler
+            .on_store(&node, &id, "foobar".to_string(), None, Instant::now()));
+        assert_eq!("foobar", svc.stored_data().get(&id).unwrap());
+    }
+
+    #[test]
+    fn test_store_ttl() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        svc.set_value_ttl(Duration::from_secs(3600));
+        let node = test::new_node(test::make_id(43));
+        let start = Instant::now();
+
+        let ttl = Some(Duration::from_secs(48 * 3600));
+        assert!(svc
+            .handler
+            .on_store(&node, &test::make_id(1), "foo".to_string(), ttl, start));
+        let ttl = Some(Duration::from_secs(60));
+        assert!(svc.handler.on_store(
+            &node,
+            &test::make_id(2),
+            "bar".to_string(),
+            ttl,
+            Instant::now()
+        ));
+        let expires = svc.stored_data().expires(&test::make_id(1)).unwrap();
+        assert_eq!(
+            svc.handler.system_time(start) + Duration::from_secs(3600),
+            expires
+        );
+
+        // Other nodes cannot make values live longer
+        let other = test::new_node(test::make_id(44));
+        let later = start + Duration::from_secs(1800);
+        assert!(svc
+            .handler
+            .on_store(&other, &test::make_id(1), "evil".to_string(), None, later));
+        assert_eq!(Some(expires), svc.stored_data().expires(&test::make_id(1)));
+        // But the publisher can
+        assert!(svc
+            .handler
+            .on_store(&node, &test::make_id(1), "foo".to_string(), None, later));
+        let expires = svc.handler.system_time(later) + Duration::from_secs(3600);
+        assert_eq!(Some(expires), svc.stored_data().expires(&test::make_id(1)));
+        // As well as anybody storing the same value
+        let later = later + Duration::from_secs(600);
+        assert!(svc
+            .handler
+            .on_store(&other, &test::make_id(1), "foo".to_string(), None, later));
+        let expires = svc.handler.system_time(later) + Duration::from_secs(3600);
+        assert_eq!(Some(expires), svc.stored_data().expires(&test::make_id(1)));
+
+        // Expired values are not returned
+        let ttl = Some(Duration::from_secs(0));
+        assert!(svc.handler.on_store(
+            &node,
+            &test::make_id(3),
+            "baz".to_string(),
+            ttl,
+            Instant::now()
+        ));
+        match svc
+            .handler
+            .on_find_value(&node, &test::make_id(3), Instant::now())
+        {
+            FindResult::ClosestNodes(..) => (),
+            res => panic!("wrong result {:?}", res),
+        }
+        assert_eq!(3, svc.stored_data().len());
+
+        assert_eq!(1, svc.remove_expired_values(Instant::now()));
+        let later = Instant::now() + Duration::from_secs(120);
+        assert_eq!(1, svc.remove_expired_values(later));
+        assert_eq!("foo", svc.stored_data().get(&test::make_id(1)).unwrap());
+    }
+
+    #[test]
+    fn test_store_peer_lists() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, Vec<u16>> =
+            Service::new(node_table, test::make_addr(8008));
+        svc.set_storage(ListStorage::new());
+        let now = Instant::now();
+        let ttl = Some(Duration::from_secs(60));
+
+        let node = test::new_node(test::make_id(43));
+        assert!(svc
+            .handler
+            .on_store(&node, &test::make_id(1), vec![6881], ttl, now));
+        let other = test::new_node(test::make_id(44));
+        assert!(svc
+            .handler
+            .on_store(&other, &test::make_id(1), vec![6882], None, now));
+        match svc.handler.on_find_value(&node, &test::make_id(1), now) {
+            FindResult::Value(peers) => assert_eq!(vec![6881, 6882], peers),
+            res => panic!("wrong result {:?}", res),
+        }
+
+        let later = now + Duration::from_secs(60);
+        match svc.handler.on_find_value(&node, &test::make_id(1), later) {
+            FindResult::Value(peers) => assert_eq!(vec![6882], peers),
+            res => panic!("wrong result {:?}", res),
+        }
+        assert_eq!(0, svc.remove_expired_values(later));
+        assert_eq!(Some(vec![6882]), svc.stored_data().get(&test::make_id(1)));
+    }
+
+    /// Storage keeping at most one value.
+    struct SingleStorage(MemoryStorage<TestsIdType, String>);
+
+    impl Storage<TestsIdType, String> for SingleStorage {
+        fn get(&self, id: &TestsIdType) -> Option<String> {
+            self.0.get(id)
+        }
+        fn expires(&self, id: &TestsIdType) -> Option<SystemTime> {
+            self.0.expires(id)
+        }
+        fn put(&mut self, id: TestsIdType, value: String, expires: SystemTime) -> bool {
+            if !self.0.is_empty() && !self.0.contains(&id) {
+                return false;
+            }
+            self.0.put(id, value, expires)
+        }
+        fn remove(&mut self, id: &TestsIdType) -> Option<String> {
+            self.0.remove(id)
+        }
+        fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (TestsIdType, String)> + 'a> {
+            Box::new(self.0.iter())
+        }
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+        fn size(&self) -> usize {
+            self.0.size()
+        }
+    }
+
+    #[test]
+    fn test_custom_storage() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        let mut handler = svc.handler();
+        svc.set_storage(SingleStorage(MemoryStorage::new()));
+        let node = test::new_node(test::make_id(43));
+
+        assert!(handler.on_store(
+            &node,
+            &test::make_id(44),
+            "foo".to_string(),
+            None,
+            Instant::now()
+        ));
+        assert!(!handler.on_store(
+            &node,
+            &test::make_id(45),
+            "bar".to_string(),
+            None,
+            Instant::now()
+        ));
+        assert!(handler.on_store(
+            &node,
+            &test::make_id(44),
+            "baz".to_string(),
+            None,
+            Instant::now()
+        ));
+        assert_eq!(1, svc.stored_data().len());
+        assert_eq!("baz", svc.stored_data().get(&test::make_id(44)).unwrap());
+    }
+
+    fn new_request(
+        payload: RequestPayload<TestsIdType, String>,
+    ) -> Request<TestsIdType, net::SocketAddr, String> {
+        Request {
+            caller: test::new_node(test::make_id(43)),
+            request_id: test::make_id(1),
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_handle() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        let id: TestsIdType = test::make_id(44);
+
+        let response = svc
+            .handler
+            .handle(new_request(RequestPayload::Ping), Instant::now());
+        let responder = response.responder.unwrap();
+        assert_eq!(test::make_id(42), responder.id);
+        assert_eq!(test::make_addr(8008), responder.address);
+        assert_eq!(test::make_id(1), response.request.request_id);
+        match response.payload {
+            ResponsePayload::NoResult => (),
+            _ => panic!("wrong payload for ping"),
+        }
+        assert_eq!(
+            test::make_id(43),
+            svc.node_table().node.as_ref().unwrap().id
+        );
+
+        let response = svc.handler.handle(
+            new_request(RequestPayload::FindNode(test::make_id(43))),
+            Instant::now(),
+        );
+        match response.payload {
+            ResponsePayload::NodesFound(nodes) => assert_eq!(1, nodes.len()),
+            _ => panic!("wrong payload for find_node"),
+        }
+
+        let response = svc.handler.handle(
+            new_request(RequestPayload::FindValue(id.clone())),
+            Instant::now(),
+        );
+        match response.payload {
+            ResponsePayload::NodesFound(nodes) => assert!(nodes.is_empty()),
+            _ => panic!("wrong payload for find_value"),
+        }
+
+        let response = svc.handler.handle(
+            new_request(RequestPayload::Store(
+                id.clone(),
+                "foobar".to_string(),
+                None,
+            )),
+            Instant::now(),
+        );
+        match response.payload {
+            ResponsePayload::NoResult => (),
+            _ => panic!("wrong payload for store"),
+        }
+
+        let response = svc.handler.handle(
+            new_request(RequestPayload::FindValue(id.clone())),
+            Instant::now(),
+        );
+        match response.payload {
+            ResponsePayload::ValueFound(value) => assert_eq!("foobar", value),
+            _ => panic!("wrong payload for find_value"),
+        }
+    }
+
+    #[test]
+    fn test_handle_store_rejected() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table, test::make_addr(8008));
+        svc.set_store_validator(|_id, _value| false);
+
+        let response = svc.handler.handle(
+            new_request(RequestPayload::Store(
+                test::make_id(44),
+                "foobar".to_string(),
+                None,
+            )),
+            Instant::now(),
+        );
+        match response.payload {
+            ResponsePayload::Error(code, _) => assert_eq!(ERROR_GENERIC, code),
+            _ => panic!("wrong payload for rejected store"),
+        }
+        assert!(svc.stored_data().is_empty());
+    }
+
+    #[test]
+    fn test_persistent() {
+        let path = env::temp_dir().join(format!("dht-test-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        let interval = Duration::from_secs(60);
+
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_persistent(
+                KNodeTable::new(test::make_id(1)),
+                test::make_addr(8008),
+                &path,
+                interval,
+            )
+            .unwrap();
+        let start = Instant::now();
+        svc.node_table_mut()
+            .update(&test::new_node(test::make_id(2)));
+        assert!(!svc.save_node_table_if_needed(start).unwrap());
+        assert!(!path.exists());
+        assert!(svc.save_node_table_if_needed(start + interval).unwrap());
+        assert!(!svc.save_node_table_if_needed(start + interval).unwrap());
+
+        // The ID comes from the file, not from the new table
+        let svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_persistent(
+                KNodeTable::new(test::make_id(3)),
+                test::make_addr(8008),
+                &path,
+                interval,
+            )
+            .unwrap();
+        assert_eq!(test::make_id(1), *svc.node_id());
+        let nodes = svc.node_table().find(&test::make_id(2), 1);
+        assert_eq!(test::make_id(2), nodes[0].id);
+
+        // Settings come from the new table
+        let mut node_table = KNodeTable::new(test::make_id(3));
+        node_table.set_node_ttl(None);
+        node_table.set_subnet_limits(SubnetLimits {
+            per_bucket: None,
+            per_table: Some(1),
+        });
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_persistent(node_table, test::make_addr(8008), &path, interval).unwrap();
+        let far_future = Instant::now() + Duration::from_secs(24 * 3600);
+        assert!(svc.node_table_mut().pop_expired(far_future).is_empty());
+        assert!(!svc
+            .node_table_mut()
+            .update(&test::new_node_with_port(test::make_id(4), 8009)));
+
+        // Sizes must match
+        let result: io::Result<Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String>> =
+            Service::new_persistent(
+                KNodeTable::new_with_details(test::make_id(3), 8, 160),
+                test::make_addr(8008),
+                &path,
+                interval,
+            );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, result.err().unwrap().kind());
+    }
 }
@@ -0,0 +1,417 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Storage for values kept by a DHT node.
+//!
+//! `Service` keeps values in a `MemoryStorage` by default, use
+//! `Service::set_storage` to plug in another implementation, e.g.
+//! `ListStorage` for lists of peers.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+use std::time::SystemTime;
+
+/// Default maximum number of entries kept in a list by `ListStorage`.
+pub static DEFAULT_MAX_ENTRIES: usize = 256;
+
+/// Trait for storage of values under their IDs.
+///
+/// Every value is stored with its expiration time. Expired values are
+/// kept until `remove_expired` is called, but are never returned to other
+/// nodes by `Service`. Values are returned by value, so that
+/// implementations do not have to keep them in memory, and expiration
+/// times are system times, so that they survive restarts.
+pub trait Storage<TId, TData>: Send + Sync {
+    /// Get a copy of the value stored under the ID.
+    fn get(&self, id: &TId) -> Option<TData>;
+    /// Get the expiration time of the value stored under the ID.
+    fn expires(&self, id: &TId) -> Option<SystemTime>;
+    /// Store the value under the ID until `expires`, replacing the previous
+    /// one.
+    ///
+    /// Returns whether the value was stored, e.g. bounded storage may
+    /// refuse new values when it is full.
+    fn put(&mut self, id: TId, value: TData, expires: SystemTime) -> bool;
+    /// Store a value received from another node at `now` until `expires`.
+    ///
+    /// `renew` is set if the value comes from the node that published the
+    /// stored one or is identical to it. By default a value that is still
+    /// alive is replaced, but unless `renew` is set the new one expires no
+    /// later than it, so that other nodes cannot make values live longer by
+    /// overwriting them.
+    fn store(
+        &mut self,
+        id: TId,
+        value: TData,
+        expires: SystemTime,
+        now: SystemTime,
+        renew: bool,
+    ) -> bool {
+        let expires = match self.expires(&id) {
+            Some(current) if current > now && !renew => cmp::min(current, expires),
+            _ => expires,
+        };
+        self.put(id, value, expires)
+    }
+    /// Remove the value stored under the ID and return it.
+    fn remove(&mut self, id: &TId) -> Option<TData>;
+    /// Iterate over copies of all stored IDs and values in no particular
+    /// order.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (TId, TData)> + 'a>;
+    /// Number of stored values.
+    fn len(&self) -> usize;
+    /// Approximate size of the stored IDs and values in bytes.
+    fn size(&self) -> usize;
+
+    /// Whether the value is stored under the ID.
+    fn contains(&self, id: &TId) -> bool {
+        self.get(id).is_some()
+    }
+    /// Whether no values are stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Get the value stored under the ID if it has not expired by `now`.
+    fn get_alive(&self, id: &TId, now: SystemTime) -> Option<TData> {
+        match self.expires(id) {
+            Some(expires) if expires > now => self.get(id),
+            _ => None,
+        }
+    }
+    /// Remove values expired by `now`, returns their number.
+    fn remove_expired(&mut self, now: SystemTime) -> usize {
+        let expired: Vec<TId> = self
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| self.expires(id).is_none_or(|expires| expires <= now))
+            .collect();
+        for id in &expired {
+            let _ = self.remove(id);
+        }
+        expired.len()
+    }
+}
+
+/// Unbounded storage in memory, used by `Service` by default.
+///
+/// Only the shallow size of IDs and values is reported by `size`, i.e.
+/// memory on the heap (e.g. contents of a `String`) is not counted.
+pub struct MemoryStorage<TId, TData> {
+    data: HashMap<TId, (TData, SystemTime)>,
+}
+
+impl<TId, TData> MemoryStorage<TId, TData>
+where
+    TId: Hash + Eq,
+{
+    pub fn new() -> MemoryStorage<TId, TData> {
+        MemoryStorage {
+            data: HashMap::new(),
+        }
+    }
+}
+
+impl<TId, TData> Default for MemoryStorage<TId, TData>
+where
+    TId: Hash + Eq,
+{
+    fn default() -> MemoryStorage<TId, TData> {
+        MemoryStorage::new()
+    }
+}
+
+impl<TId, TData> Storage<TId, TData> for MemoryStorage<TId, TData>
+where
+    TId: Hash + Eq + Clone + Send + Sync,
+    TData: Clone + Send + Sync,
+{
+    fn get(&self, id: &TId) -> Option<TData> {
+        self.data.get(id).map(|(value, _)| value.clone())
+    }
+
+    fn expires(&self, id: &TId) -> Option<SystemTime> {
+        self.data.get(id).map(|&(_, expires)| expires)
+    }
+
+    fn put(&mut self, id: TId, value: TData, expires: SystemTime) -> bool {
+        let _ = self.data.insert(id, (value, expires));
+        true
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<TData> {
+        self.data.remove(id).map(|(value, _)| value)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (TId, TData)> + 'a> {
+        Box::new(
+            self.data
+                .iter()
+                .map(|(id, (value, _))| (id.clone(), value.clone())),
+        )
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn size(&self) -> usize {
+        self.data.len() * mem::size_of::<(TId, (TData, SystemTime))>()
+    }
+}
+
+/// Storage for values that are lists of entries, e.g. peers announced
+/// through `protocol::krpc`.
+///
+/// Stored lists are merged with the existing ones instead of replacing
+/// them, and every entry expires separately: `expires` of a list is the
+/// latest expiration time of its entries and only alive entries are
+/// returned by `get_alive`. Lists are bounded, entries expiring first are
+/// dropped to make room for new ones.
+pub struct ListStorage<TId, TEntry> {
+    data: HashMap<TId, Vec<(TEntry, SystemTime)>>,
+    max_entries: usize,
+}
+
+impl<TId, TEntry> ListStorage<TId, TEntry>
+where
+    TId: Hash + Eq,
+{
+    /// Create a storage keeping up to `DEFAULT_MAX_ENTRIES` per list.
+    pub fn new() -> ListStorage<TId, TEntry> {
+        ListStorage::with_max_entries(DEFAULT_MAX_ENTRIES)
+    }
+
+    /// Create a storage keeping up to `max_entries` per list.
+    pub fn with_max_entries(max_entries: usize) -> ListStorage<TId, TEntry> {
+        assert!(max_entries > 0);
+        ListStorage {
+            data: HashMap::new(),
+            max_entries,
+        }
+    }
+}
+
+impl<TId, TEntry> Default for ListStorage<TId, TEntry>
+where
+    TId: Hash + Eq,
+{
+    fn default() -> ListStorage<TId, TEntry> {
+        ListStorage::new()
+    }
+}
+
+impl<TId, TEntry> Storage<TId, Vec<TEntry>> for ListStorage<TId, TEntry>
+where
+    TId: Hash + Eq + Clone + Send + Sync,
+    TEntry: PartialEq + Clone + Send + Sync,
+{
+    fn get(&self, id: &TId) -> Option<Vec<TEntry>> {
+        let entries = self.data.get(id)?;
+        Some(entries.iter().map(|(entry, _)| entry.clone()).collect())
+    }
+
+    fn expires(&self, id: &TId) -> Option<SystemTime> {
+        self.data.get(id)?.iter().map(|&(_, expires)| expires).max()
+    }
+
+    /// Add the entries, refreshing the expiration time of the known ones.
+    fn put(&mut self, id: TId, value: Vec<TEntry>, expires: SystemTime) -> bool {
+        if value.is_empty() {
+            return false;
+        }
+        let entries = self.data.entry(id).or_default();
+        for entry in value {
+            match entries.iter_mut().find(|(known, _)| *known == entry) {
+                Some(known) => known.1 = expires,
+                None => entries.push((entry, expires)),
+            }
+        }
+        while entries.len() > self.max_entries {
+            let first = (0..entries.len()).min_by_key(|&i| entries[i].1).unwrap();
+            let _ = entries.remove(first);
+        }
+        true
+    }
+
+    /// Entries are merged by `put`, so that every one lives on its own.
+    fn store(
+        &mut self,
+        id: TId,
+        value: Vec<TEntry>,
+        expires: SystemTime,
+        _now: SystemTime,
+        _renew: bool,
+    ) -> bool {
+        self.put(id, value, expires)
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<Vec<TEntry>> {
+        let entries = self.data.remove(id)?;
+        Some(entries.into_iter().map(|(entry, _)| entry).collect())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (TId, Vec<TEntry>)> + 'a> {
+        Box::new(self.data.iter().map(|(id, entries)| {
+            let entries = entries.iter().map(|(entry, _)| entry.clone()).collect();
+            (id.clone(), entries)
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn size(&self) -> usize {
+        let entries: usize = self.data.values().map(|entries| entries.len()).sum();
+        self.data.len() * mem::size_of::<TId>() + entries * mem::size_of::<(TEntry, SystemTime)>()
+    }
+
+    fn get_alive(&self, id: &TId, now: SystemTime) -> Option<Vec<TEntry>> {
+        let alive: Vec<_> = self
+            .data
+            .get(id)?
+            .iter()
+            .filter(|&&(_, expires)| expires > now)
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        if alive.is_empty() {
+            None
+        } else {
+            Some(alive)
+        }
+    }
+
+    /// Remove entries expired by `now`, returns the number of lists left
+    /// without entries and removed as well.
+    fn remove_expired(&mut self, now: SystemTime) -> usize {
+        let before = self.data.len();
+        for entries in self.data.values_mut() {
+            entries.retain(|&(_, expires)| expires > now);
+        }
+        self.data.retain(|_, entries| !entries.is_empty());
+        before - self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use std::time::{Duration, SystemTime};
+
+    use super::super::utils::test;
+    use super::{ListStorage, MemoryStorage, Storage};
+
+    #[test]
+    fn test_memory_storage() {
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let mut storage: Box<dyn Storage<test::IdType, u64>> = Box::new(MemoryStorage::new());
+        assert!(storage.is_empty());
+        assert_eq!(0, storage.size());
+
+        assert!(storage.put(test::make_id(1), 42, expires));
+        assert!(storage.put(test::make_id(2), 43, expires));
+        assert!(storage.put(test::make_id(1), 44, expires));
+        assert_eq!(2, storage.len());
+        assert_eq!(Some(44), storage.get(&test::make_id(1)));
+        assert_eq!(Some(expires), storage.expires(&test::make_id(1)));
+        assert!(storage.contains(&test::make_id(2)));
+        assert!(storage.size() >= 2 * (mem::size_of::<test::IdType>() + mem::size_of::<u64>()));
+
+        let mut values: Vec<_> = storage.iter().map(|(_, value)| value).collect();
+        values.sort();
+        assert_eq!(vec![43, 44], values);
+
+        assert_eq!(Some(43), storage.remove(&test::make_id(2)));
+        assert!(storage.remove(&test::make_id(2)).is_none());
+        assert!(!storage.contains(&test::make_id(2)));
+        assert!(storage.expires(&test::make_id(2)).is_none());
+        assert_eq!(1, storage.len());
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let now = SystemTime::now();
+        let mut storage = MemoryStorage::new();
+        storage.put(test::make_id(1), 42, now);
+        storage.put(test::make_id(2), 43, now + Duration::from_secs(60));
+        assert!(storage.get_alive(&test::make_id(1), now).is_none());
+        assert_eq!(Some(43), storage.get_alive(&test::make_id(2), now));
+        assert!(storage.get_alive(&test::make_id(3), now).is_none());
+
+        assert_eq!(1, storage.remove_expired(now));
+        assert!(!storage.contains(&test::make_id(1)));
+        assert_eq!(1, storage.remove_expired(now + Duration::from_secs(60)));
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn test_store_extend() {
+        let now = SystemTime::now();
+        let minutes = |m: u64| now + Duration::from_secs(m * 60);
+        let mut storage = MemoryStorage::new();
+        assert!(storage.store(test::make_id(1), 42, minutes(10), now, false));
+        // Replaced, but not for longer
+        assert!(storage.store(test::make_id(1), 43, minutes(60), now, false));
+        assert_eq!(Some(43), storage.get(&test::make_id(1)));
+        assert_eq!(Some(minutes(10)), storage.expires(&test::make_id(1)));
+        // Renewed
+        assert!(storage.store(test::make_id(1), 43, minutes(30), now, true));
+        assert_eq!(Some(minutes(30)), storage.expires(&test::make_id(1)));
+        // Expired values are not a limit
+        assert!(storage.store(test::make_id(1), 44, minutes(60), minutes(30), false));
+        assert_eq!(Some(minutes(60)), storage.expires(&test::make_id(1)));
+    }
+
+    #[test]
+    fn test_list_storage() {
+        let now = SystemTime::now();
+        let minutes = |m: u64| now + Duration::from_secs(m * 60);
+        let mut storage: Box<dyn Storage<test::IdType, Vec<u16>>> = Box::new(ListStorage::new());
+        assert!(!storage.put(test::make_id(1), vec![], minutes(10)));
+        assert!(storage.is_empty());
+
+        assert!(storage.store(test::make_id(1), vec![1, 2], minutes(10), now, false));
+        assert!(storage.store(test::make_id(1), vec![3], minutes(30), now, false));
+        // Refreshed on its own
+        assert!(storage.store(test::make_id(1), vec![1], minutes(20), now, false));
+        assert!(storage.store(test::make_id(2), vec![4], minutes(10), now, false));
+        assert_eq!(2, storage.len());
+        assert_eq!(Some(vec![1, 2, 3]), storage.get(&test::make_id(1)));
+        assert_eq!(Some(minutes(30)), storage.expires(&test::make_id(1)));
+        assert!(storage.size() >= 4 * mem::size_of::<u16>());
+
+        assert_eq!(
+            Some(vec![1, 3]),
+            storage.get_alive(&test::make_id(1), minutes(10))
+        );
+        assert!(storage.get_alive(&test::make_id(2), minutes(10)).is_none());
+
+        assert_eq!(1, storage.remove_expired(minutes(10)));
+        assert_eq!(Some(vec![1, 3]), storage.get(&test::make_id(1)));
+        assert_eq!(0, storage.remove_expired(minutes(20)));
+        assert_eq!(Some(vec![3]), storage.remove(&test::make_id(1)));
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn test_list_storage_bounded() {
+        let now = SystemTime::now();
+        let minutes = |m: u64| now + Duration::from_secs(m * 60);
+        let mut storage = ListStorage::with_max_entries(2);
+        assert!(storage.put(test::make_id(1), vec![1], minutes(20)));
+        assert!(storage.put(test::make_id(1), vec![2], minutes(10)));
+        // Entry expiring first is dropped
+        assert!(storage.put(test::make_id(1), vec![3], minutes(30)));
+        assert_eq!(Some(vec![1, 3]), storage.get(&test::make_id(1)));
+        assert!(storage.put(test::make_id(1), vec![4, 5], minutes(30)));
+        assert_eq!(2, storage.get(&test::make_id(1)).unwrap().len());
+    }
+}
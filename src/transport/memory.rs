@@ -0,0 +1,544 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! In-memory transport for deterministic simulations.
+//!
+//! All nodes live in one process and are connected through a `Network`,
+//! which delivers requests directly to their handlers. The network can
+//! lose messages, add latency and split nodes into partitions. Random
+//! decisions are taken with a seeded generator and time is measured with
+//! a virtual clock, so the same scenario with the same seed always gives
+//! the same results.
+//!
+//! Messages are delivered synchronously from `Transport::send`: every
+//! delivered message advances the clock by its latency, every lost one by
+//! the timeout. Handlers registered in the network take request IDs from
+//! its generator and get the current time from its clock. After handling
+//! a request, receivers ping their oldest nodes if needed (see
+//! `Handler::ping_oldest`).
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+
+use super::super::protocol::{Request, Response};
+use super::super::service::Handler;
+use super::super::{GenericId, GenericNodeTable};
+use super::Transport;
+
+/// Default time after which a lost message is considered failed.
+pub static DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Address of a node in a simulated network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SimAddr(pub u32);
+
+struct State<TId, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TNodeTable: GenericNodeTable<TId, SimAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    handlers: HashMap<SimAddr, Handler<TId, SimAddr, TNodeTable, TData>>,
+    blocked: HashSet<(SimAddr, SimAddr)>,
+    rng: XorShiftRng,
+    start: Instant,
+    elapsed: Duration,
+    latency: (Duration, Duration),
+    loss: f64,
+    timeout: Duration,
+}
+
+/// Simulated network connecting handlers of many services.
+///
+/// Cloned networks share the same state.
+pub struct Network<TId, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TNodeTable: GenericNodeTable<TId, SimAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    state: Arc<Mutex<State<TId, TNodeTable, TData>>>,
+}
+
+/// Transport sending requests from one node of a simulated network.
+pub struct MemoryTransport<TId, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TNodeTable: GenericNodeTable<TId, SimAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    network: Network<TId, TNodeTable, TData>,
+    address: SimAddr,
+}
+
+impl<TId, TNodeTable, TData> Network<TId, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TNodeTable: GenericNodeTable<TId, SimAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    /// Create a reliable network without latency.
+    ///
+    /// `seed` initializes the random generator used for all decisions.
+    pub fn new(seed: u64) -> Network<TId, TNodeTable, TData> {
+        Network {
+            state: Arc::new(Mutex::new(State {
+                handlers: HashMap::new(),
+                blocked: HashSet::new(),
+                rng: XorShiftRng::seed_from_u64(seed),
+                start: Instant::now(),
+                elapsed: Duration::from_secs(0),
+                latency: (Duration::from_secs(0), Duration::from_secs(0)),
+                loss: 0.0,
+                timeout: DEFAULT_TIMEOUT,
+            })),
+        }
+    }
+
+    /// Set latency of every message to a random value in the given range.
+    pub fn set_latency(&self, min: Duration, max: Duration) {
+        assert!(min <= max);
+        self.state.lock().unwrap().latency = (min, max);
+    }
+    /// Set probability of losing every message.
+    pub fn set_loss(&self, loss: f64) {
+        assert!((0.0..=1.0).contains(&loss));
+        self.state.lock().unwrap().loss = loss;
+    }
+    /// Set time after which a lost message is considered failed.
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.state.lock().unwrap().timeout = timeout;
+    }
+
+    /// Attach a node to the network, replacing any node with its address.
+    ///
+    /// The handler generates request IDs with a seed taken from the network.
+    pub fn register(&self, handler: Handler<TId, SimAddr, TNodeTable, TData>) {
+        let address = *handler.address();
+        let mut state = self.state.lock().unwrap();
+        handler.seed_rng(state.rng.gen());
+        state.handlers.insert(address, handler);
+    }
+    /// Detach a node from the network, its requests will time out.
+    pub fn unregister(&self, address: &SimAddr) {
+        self.state.lock().unwrap().handlers.remove(address);
+    }
+
+    /// Forbid communication between any node of `group1` and `group2`.
+    pub fn partition(&self, group1: &[SimAddr], group2: &[SimAddr]) {
+        let mut state = self.state.lock().unwrap();
+        for addr1 in group1 {
+            for addr2 in group2 {
+                state.blocked.insert((*addr1, *addr2));
+                state.blocked.insert((*addr2, *addr1));
+            }
+        }
+    }
+    /// Remove all partitions.
+    pub fn heal(&self) {
+        self.state.lock().unwrap().blocked.clear();
+    }
+
+    /// Current time of the virtual clock.
+    pub fn now(&self) -> Instant {
+        let state = self.state.lock().unwrap();
+        state.start + state.elapsed
+    }
+    /// Advance the virtual clock.
+    pub fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().elapsed += duration;
+    }
+
+    /// Create a transport sending requests from the given address.
+    pub fn transport(&self, address: SimAddr) -> MemoryTransport<TId, TNodeTable, TData> {
+        MemoryTransport {
+            network: self.clone(),
+            address,
+        }
+    }
+
+    fn deliver(
+        &self,
+        from: SimAddr,
+        to: SimAddr,
+        request: Request<TId, SimAddr, TData>,
+    ) -> Option<Response<TId, SimAddr, TData>> {
+        let mut handler = {
+            let mut state = self.state.lock().unwrap();
+            let handler = match state.handlers.get(&to) {
+                Some(handler) if !state.blocked.contains(&(from, to)) => handler.clone(),
+                _ => {
+                    debug!("Node {:?} is not reachable from {:?}", to, from);
+                    state.fail();
+                    return None;
+                }
+            };
+            if !state.transfer() {
+                debug!("Request from {:?} to {:?} lost", from, to);
+                return None;
+            }
+            handler
+        };
+
+        // The lock is not held while handling, handlers may use the network
+        let response = handler.handle(request, self.now());
+        handler.ping_oldest(&self.transport(to), self.now());
+
+        if self.state.lock().unwrap().transfer() {
+            Some(response)
+        } else {
+            debug!("Response from {:?} to {:?} lost", to, from);
+            None
+        }
+    }
+}
+
+impl<TId, TNodeTable, TData> Clone for Network<TId, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TNodeTable: GenericNodeTable<TId, SimAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    fn clone(&self) -> Network<TId, TNodeTable, TData> {
+        Network {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<TId, TNodeTable, TData> State<TId, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TNodeTable: GenericNodeTable<TId, SimAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    /// Decide whether a message gets through and advance the clock.
+    fn transfer(&mut self) -> bool {
+        if self.loss > 0.0 && self.rng.gen_bool(self.loss) {
+            self.fail();
+            return false;
+        }
+        let (min, max) = self.latency;
+        let latency = if min == max {
+            min
+        } else {
+            min + (max - min).mul_f64(self.rng.gen::<f64>())
+        };
+        self.elapsed += latency;
+        true
+    }
+
+    fn fail(&mut self) {
+        self.elapsed += self.timeout;
+    }
+}
+
+impl<TId, TNodeTable, TData> MemoryTransport<TId, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TNodeTable: GenericNodeTable<TId, SimAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    /// Address requests are sent from.
+    pub fn address(&self) -> &SimAddr {
+        &self.address
+    }
+}
+
+impl<TId, TNodeTable, TData> Transport<TId, SimAddr, TData>
+    for MemoryTransport<TId, TNodeTable, TData>
+where
+    TId: GenericId + 'static,
+    TNodeTable: GenericNodeTable<TId, SimAddr> + 'static,
+    TData: Send + Sync + Clone + PartialEq + 'static,
+{
+    fn send<F>(&self, address: &SimAddr, mut request: Request<TId, SimAddr, TData>, callback: F)
+    where
+        F: FnOnce(Option<Response<TId, SimAddr, TData>>) + Send + 'static,
+    {
+        request.caller.address = self.address;
+        callback(self.network.deliver(self.address, *address, request));
+    }
+
+    fn now(&self) -> Instant {
+        self.network.now()
+    }
+
+    fn clock(&self) -> Box<dyn Fn() -> Instant + Send> {
+        let network = self.network.clone();
+        Box::new(move || network.now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::super::super::protocol::{Request, RequestPayload};
+    use super::super::super::service::{BootstrapError, REFRESH_INTERVAL, VALUE_TTL};
+    use super::super::super::utils::test;
+    use super::super::super::{GenericNodeTable, KNodeTable, Node, Service};
+    use super::super::Transport;
+    use super::{Network, SimAddr};
+
+    type TestTable = KNodeTable<test::IdType, SimAddr>;
+    type TestService = Service<test::IdType, SimAddr, TestTable, String>;
+    type TestNetwork = Network<test::IdType, TestTable, String>;
+
+    /// Create services with IDs 0..count, node i knowing i+1 and 2i+1.
+    fn prepare(network: &TestNetwork, count: u8) -> Vec<TestService> {
+        let mut services: Vec<TestService> = (0..count)
+            .map(|i| {
+                let table = KNodeTable::new(test::make_id(i));
+                Service::new_with_id(table, test::make_id(i), SimAddr(i as u32))
+            })
+            .collect();
+        for (i, svc) in services.iter_mut().enumerate() {
+            for known in &[(i + 1) % count as usize, (2 * i + 1) % count as usize] {
+                if *known != i {
+                    svc.node_table_mut().update(&Node {
+                        id: test::make_id(*known as u8),
+                        address: SimAddr(*known as u32),
+                    });
+                }
+            }
+            network.register(svc.handler());
+        }
+        services
+    }
+
+    fn lookup(network: &TestNetwork, svc: &mut TestService, id: u8) -> Vec<test::IdType> {
+        let transport = network.transport(*svc.address());
+        svc.lookup_node(&transport, &test::make_id(id))
+            .into_iter()
+            .map(|n| n.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let network = Network::new(42);
+        let start = network.now();
+        let mut services = prepare(&network, 64);
+        let result = lookup(&network, &mut services[0], 37);
+        assert_eq!(test::make_id(37), result[0]);
+        // No latency and no losses
+        assert_eq!(start, network.now());
+        network.advance(Duration::from_secs(1));
+        assert_eq!(start + Duration::from_secs(1), network.now());
+    }
+
+    #[test]
+    fn test_rtt() {
+        let network = Network::new(42);
+        network.set_latency(Duration::from_millis(50), Duration::from_millis(50));
+        let mut services = prepare(&network, 4);
+        lookup(&network, &mut services[0], 2);
+        // Measured with the virtual clock: request and response latency
+        let table = services[0].node_table();
+        let info = table.node_info(&test::make_id(1)).unwrap();
+        assert_eq!(Some(Duration::from_millis(100)), info.rtt);
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let run = |seed| {
+            let network = Network::new(seed);
+            network.set_loss(0.3);
+            network.set_latency(Duration::from_millis(10), Duration::from_millis(100));
+            let start = network.now();
+            let mut services = prepare(&network, 64);
+            let result = lookup(&network, &mut services[3], 50);
+            (result, network.now() - start)
+        };
+        let (result1, elapsed1) = run(42);
+        let (result2, elapsed2) = run(42);
+        assert_eq!(result1, result2);
+        assert_eq!(elapsed1, elapsed2);
+        assert!(elapsed1 > Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_partition() {
+        let network = Network::new(42);
+        let mut services = prepare(&network, 4);
+        // Node 0 only knows node 1
+        network.partition(&[SimAddr(0)], &[SimAddr(1), SimAddr(2), SimAddr(3)]);
+        assert!(lookup(&network, &mut services[0], 2).is_empty());
+        network.heal();
+        assert_eq!(test::make_id(2), lookup(&network, &mut services[0], 2)[0]);
+    }
+
+    #[test]
+    fn test_churn_and_replication() {
+        let network = Network::new(42);
+        let mut services = prepare(&network, 16);
+        services[9].stored_data_mut().put(
+            test::make_id(8),
+            "foobar".to_string(),
+            SystemTime::now() + VALUE_TTL,
+        );
+        services[12].stored_data_mut().put(
+            test::make_id(8),
+            "foobar".to_string(),
+            SystemTime::now() + VALUE_TTL,
+        );
+
+        let transport = network.transport(SimAddr(0));
+        assert_eq!(
+            Some("foobar".to_string()),
+            services[0].get(&transport, &test::make_id(8))
+        );
+
+        network.unregister(&SimAddr(9));
+        network.unregister(&SimAddr(12));
+        let transport = network.transport(SimAddr(1));
+        // The value was cached on the way during the first lookup
+        assert_eq!(
+            Some("foobar".to_string()),
+            services[1].get(&transport, &test::make_id(8))
+        );
+    }
+
+    #[test]
+    fn test_value_expires() {
+        let network = Network::new(42);
+        let mut services = prepare(&network, 16);
+        let transport = network.transport(SimAddr(0));
+        assert!(services[0]
+            .put(&transport, &test::make_id(8), "foobar".to_string())
+            .is_ok());
+
+        let transport = network.transport(SimAddr(1));
+        network.advance(VALUE_TTL - Duration::from_secs(1));
+        assert_eq!(
+            Some("foobar".to_string()),
+            services[1].get(&transport, &test::make_id(8))
+        );
+        network.advance(Duration::from_secs(1));
+        assert!(services[1].get(&transport, &test::make_id(8)).is_none());
+    }
+
+    #[test]
+    fn test_ping_before_evict() {
+        let network = Network::new(42);
+        let table = KNodeTable::new_with_details(test::make_id(0), 1, 160);
+        let mut svc: TestService = Service::new_with_id(table, test::make_id(0), SimAddr(0));
+        svc.node_table_mut().update(&Node {
+            id: test::make_id(2),
+            address: SimAddr(2),
+        });
+        network.register(svc.handler());
+        for i in 2..4 {
+            let other: TestService = Service::new_with_id(
+                KNodeTable::new(test::make_id(i)),
+                test::make_id(i),
+                SimAddr(i as u32),
+            );
+            network.register(other.handler());
+        }
+
+        // Node 3 falls into the same full bucket as node 2
+        let ping = || {
+            let request = Request {
+                caller: Node {
+                    id: test::make_id(3),
+                    address: SimAddr(3),
+                },
+                request_id: test::make_id(99),
+                payload: RequestPayload::Ping,
+            };
+            network
+                .transport(SimAddr(3))
+                .send(&SimAddr(0), request, |response| assert!(response.is_some()));
+        };
+        let known = |svc: &TestService| -> Vec<test::IdType> {
+            svc.node_table()
+                .find(&test::make_id(0), 10)
+                .into_iter()
+                .map(|n| n.id)
+                .collect()
+        };
+
+        // Node 2 answers the ping and stays
+        ping();
+        assert!(!svc.clean_needed());
+        assert_eq!(vec![test::make_id(2)], known(&svc));
+
+        // Node 2 is gone and gets replaced
+        network.unregister(&SimAddr(2));
+        ping();
+        assert!(!svc.clean_needed());
+        assert_eq!(vec![test::make_id(3)], known(&svc));
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let network = Network::new(42);
+        let services = prepare(&network, 64);
+        let table = KNodeTable::new_with_details(test::make_id(200), 32, 8);
+        let mut svc: TestService = Service::new_with_id(table, test::make_id(200), SimAddr(200));
+        network.register(svc.handler());
+        let transport = network.transport(SimAddr(200));
+
+        assert_eq!(Ok(1), svc.bootstrap(&transport, &[SimAddr(5)]));
+        // 200 = 0b11001000, the closest nodes are 0b11xxxxxx
+        let known = svc.node_table().find(&test::make_id(200), 64);
+        assert!(known.len() > 16);
+        assert!(known.iter().all(|n| n.id != test::make_id(200)));
+        // Buckets further than the closest neighbour are populated too
+        assert!(known.iter().any(|n| n.id[0] < 0b1000_0000));
+        // Others learned about us
+        let found = services[5].node_table().find(&test::make_id(200), 1);
+        assert_eq!(test::make_id(200), found[0].id);
+    }
+
+    #[test]
+    fn test_bootstrap_no_seeds() {
+        let network: TestNetwork = Network::new(42);
+        let mut svc: TestService = Service::new_with_id(
+            KNodeTable::new(test::make_id(1)),
+            test::make_id(1),
+            SimAddr(1),
+        );
+        let transport = network.transport(SimAddr(1));
+        assert_eq!(
+            Err(BootstrapError::NoSeedAnswered),
+            svc.bootstrap(&transport, &[SimAddr(2), SimAddr(3)])
+        );
+        assert_eq!(
+            Err(BootstrapError::NoSeedAnswered),
+            svc.bootstrap(&transport, &[])
+        );
+    }
+
+    #[test]
+    fn test_refresh_buckets() {
+        let network = Network::new(42);
+        let _services = prepare(&network, 64);
+        let table = KNodeTable::new_with_details(test::make_id(200), 32, 8);
+        let mut svc: TestService = Service::new_with_id(table, test::make_id(200), SimAddr(200));
+        svc.node_table_mut().update(&Node {
+            id: test::make_id(1),
+            address: SimAddr(1),
+        });
+        network.register(svc.handler());
+        let transport = network.transport(SimAddr(200));
+
+        assert_eq!(0, svc.refresh_buckets(&transport, network.now()));
+        // The table was created a bit later than the network
+        network.advance(REFRESH_INTERVAL + Duration::from_secs(1));
+        assert_eq!(8, svc.refresh_buckets(&transport, network.now()));
+        assert!(svc.node_table().find(&test::make_id(200), 64).len() > 16);
+        assert_eq!(0, svc.refresh_buckets(&transport, network.now()));
+    }
+}
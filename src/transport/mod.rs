@@ -0,0 +1,41 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Transports used by the service to talk to other nodes.
+
+use std::time::Instant;
+
+use super::protocol::{Request, Response};
+
+pub mod memory;
+pub mod udp;
+
+/// Trait for a transport delivering outgoing requests.
+///
+/// Implementations should not wait for the response: the callback may be
+/// called either from `send` itself or later from a different thread.
+pub trait Transport<TId, TAddr, TValue>: Send + Sync {
+    /// Send a request to the given address.
+    ///
+    /// `callback` must be called exactly once, with `None` if no valid
+    /// response was received (e.g. on timeout).
+    fn send<F>(&self, address: &TAddr, request: Request<TId, TAddr, TValue>, callback: F)
+    where
+        F: FnOnce(Option<Response<TId, TAddr, TValue>>) + Send + 'static;
+    /// Current time as seen by this transport.
+    ///
+    /// The service uses it for responses received through the transport.
+    /// Defaults to the system clock, simulated networks use a virtual one.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    /// Clock returning the same time as `now`, to be used from callbacks.
+    fn clock(&self) -> Box<dyn Fn() -> Instant + Send> {
+        Box::new(Instant::now)
+    }
+}
@@ -0,0 +1,422 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! UDP transport.
+//!
+//! Every datagram carries exactly one request or response, encoded by a
+//! user-supplied `Protocol`. Incoming requests are passed to a `Handler`
+//! in a separate thread, responses are matched to outgoing requests by
+//! their request ID. Addresses of callers and responders are always taken
+//! from the datagram source, not from its content. When newcomers do not
+//! fit into the node table, the listening thread pings the oldest nodes
+//! using `Handler::ping_oldest`.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::net;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::super::protocol::{Protocol, Request, Response};
+use super::super::service::Handler;
+use super::super::GenericNodeTable;
+use super::Transport;
+
+/// Default time to wait for a response.
+pub static DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the listening thread checks for expired requests.
+static POLL_INTERVAL: Duration = Duration::from_millis(100);
+static MAX_DATAGRAM_SIZE: usize = 65536;
+
+type Callback<TId, TValue> = Box<dyn FnOnce(Option<Response<TId, net::SocketAddr, TValue>>) + Send>;
+
+struct Pending<TProtocol: Protocol> {
+    address: net::SocketAddr,
+    request: Request<TProtocol::Id, net::SocketAddr, TProtocol::Value>,
+    deadline: Instant,
+    callback: Callback<TProtocol::Id, TProtocol::Value>,
+}
+
+struct Inner<TProtocol: Protocol> {
+    socket: net::UdpSocket,
+    protocol: TProtocol,
+    timeout: Duration,
+    pending: Mutex<HashMap<TProtocol::Id, Pending<TProtocol>>>,
+    stopped: AtomicBool,
+}
+
+/// Transport sending and receiving datagrams over a UDP socket.
+///
+/// Stops the listening thread when dropped.
+pub struct UdpTransport<TProtocol>
+where
+    TProtocol: Protocol<Addr = net::SocketAddr>,
+{
+    inner: Arc<Inner<TProtocol>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl<TProtocol> UdpTransport<TProtocol>
+where
+    TProtocol: Protocol<Addr = net::SocketAddr> + Sync + 'static,
+    TProtocol::Value: Clone + PartialEq + 'static,
+{
+    /// Start serving requests on the socket with the given handler.
+    ///
+    /// The socket must be bound to the address the service was created
+    /// with.
+    pub fn new<TNodeTable>(
+        socket: net::UdpSocket,
+        protocol: TProtocol,
+        handler: Handler<TProtocol::Id, net::SocketAddr, TNodeTable, TProtocol::Value>,
+    ) -> io::Result<UdpTransport<TProtocol>>
+    where
+        TNodeTable: GenericNodeTable<TProtocol::Id, net::SocketAddr> + 'static,
+    {
+        UdpTransport::new_with_timeout(socket, protocol, handler, DEFAULT_TIMEOUT)
+    }
+
+    /// Start serving requests with a custom response timeout.
+    pub fn new_with_timeout<TNodeTable>(
+        socket: net::UdpSocket,
+        protocol: TProtocol,
+        mut handler: Handler<TProtocol::Id, net::SocketAddr, TNodeTable, TProtocol::Value>,
+        timeout: Duration,
+    ) -> io::Result<UdpTransport<TProtocol>>
+    where
+        TNodeTable: GenericNodeTable<TProtocol::Id, net::SocketAddr> + 'static,
+    {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let inner = Arc::new(Inner {
+            socket,
+            protocol,
+            timeout,
+            pending: Mutex::new(HashMap::new()),
+            stopped: AtomicBool::new(false),
+        });
+
+        let thread_inner = inner.clone();
+        let thread = thread::Builder::new()
+            .name("dht-udp".to_string())
+            .spawn(move || thread_inner.run(&mut handler))?;
+
+        Ok(UdpTransport {
+            inner,
+            thread: Some(thread),
+        })
+    }
+
+    /// Local address of the socket.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+}
+
+impl<TProtocol> Transport<TProtocol::Id, net::SocketAddr, TProtocol::Value>
+    for UdpTransport<TProtocol>
+where
+    TProtocol: Protocol<Addr = net::SocketAddr> + Sync + 'static,
+    TProtocol::Value: Clone + PartialEq + 'static,
+{
+    fn send<F>(
+        &self,
+        address: &net::SocketAddr,
+        request: Request<TProtocol::Id, net::SocketAddr, TProtocol::Value>,
+        callback: F,
+    ) where
+        F: FnOnce(Option<Response<TProtocol::Id, net::SocketAddr, TProtocol::Value>>)
+            + Send
+            + 'static,
+    {
+        self.inner
+            .send_request(address, request, Box::new(callback));
+    }
+}
+
+// Used by the listening thread to send requests on behalf of the handler
+impl<TProtocol> Transport<TProtocol::Id, net::SocketAddr, TProtocol::Value> for Inner<TProtocol>
+where
+    TProtocol: Protocol<Addr = net::SocketAddr> + Sync + 'static,
+    TProtocol::Value: Clone + PartialEq + 'static,
+{
+    fn send<F>(
+        &self,
+        address: &net::SocketAddr,
+        request: Request<TProtocol::Id, net::SocketAddr, TProtocol::Value>,
+        callback: F,
+    ) where
+        F: FnOnce(Option<Response<TProtocol::Id, net::SocketAddr, TProtocol::Value>>)
+            + Send
+            + 'static,
+    {
+        self.send_request(address, request, Box::new(callback));
+    }
+}
+
+impl<TProtocol> Drop for UdpTransport<TProtocol>
+where
+    TProtocol: Protocol<Addr = net::SocketAddr>,
+{
+    fn drop(&mut self) {
+        self.inner.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("UDP listening thread panicked");
+            }
+        }
+    }
+}
+
+impl<TProtocol> Inner<TProtocol>
+where
+    TProtocol: Protocol<Addr = net::SocketAddr>,
+    TProtocol::Value: Clone + PartialEq,
+{
+    fn run<TNodeTable>(
+        &self,
+        handler: &mut Handler<TProtocol::Id, net::SocketAddr, TNodeTable, TProtocol::Value>,
+    ) where
+        TNodeTable: GenericNodeTable<TProtocol::Id, net::SocketAddr> + 'static,
+        Self: Transport<TProtocol::Id, net::SocketAddr, TProtocol::Value>,
+        TProtocol::Id: 'static,
+        TProtocol::Value: 'static,
+    {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        while !self.stopped.load(Ordering::SeqCst) {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((size, source)) => self.process(&buffer[..size], source, handler),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => warn!("Failed to receive a datagram: {}", e),
+            }
+            let now = Instant::now();
+            self.expire(Some(now));
+            handler.ping_oldest(self, now);
+        }
+        self.expire(None);
+    }
+
+    fn process<TNodeTable>(
+        &self,
+        data: &[u8],
+        source: net::SocketAddr,
+        handler: &mut Handler<TProtocol::Id, net::SocketAddr, TNodeTable, TProtocol::Value>,
+    ) where
+        TNodeTable: GenericNodeTable<TProtocol::Id, net::SocketAddr>,
+    {
+        if let Some(id) = self.protocol.response_id(data) {
+            let pending = {
+                let mut pending = self.pending.lock().unwrap();
+                match pending.get(&id) {
+                    Some(p) if p.address == source => pending.remove(&id),
+                    _ => None,
+                }
+            };
+            match pending {
+                Some(pending) => {
+                    let result = self.protocol.parse_response(pending.request, data, &source);
+                    match result {
+                        Ok(mut response) => {
+                            if let Some(ref mut responder) = response.responder {
+                                responder.address = source;
+                            }
+                            (pending.callback)(Some(response));
+                        }
+                        Err(e) => {
+                            warn!("Invalid response from {}: {}", source, e);
+                            (pending.callback)(None);
+                        }
+                    }
+                }
+                None => debug!("Unexpected response {:?} from {}", id, source),
+            }
+            return;
+        }
+
+        let reply = match self.protocol.parse_request(data, &source) {
+            Ok(mut request) => {
+                request.caller.address = source;
+                let response = handler.handle(request, Instant::now());
+                Some(self.protocol.format_response(response))
+            }
+            Err(e) => {
+                warn!("Invalid request from {}: {}", source, e);
+                self.protocol.format_error(data, &e)
+            }
+        };
+        if let Some(reply) = reply {
+            if let Err(e) = self.socket.send_to(&reply, source) {
+                warn!("Failed to send a response to {}: {}", source, e);
+            }
+        }
+    }
+
+    fn send_request(
+        &self,
+        address: &net::SocketAddr,
+        request: Request<TProtocol::Id, net::SocketAddr, TProtocol::Value>,
+        callback: Callback<TProtocol::Id, TProtocol::Value>,
+    ) {
+        let data = self.protocol.format_request(&request, address);
+        let id = request.request_id.clone();
+        let duplicate = match self.pending.lock().unwrap().entry(id.clone()) {
+            Entry::Occupied(..) => Some(callback),
+            Entry::Vacant(entry) => {
+                let _ = entry.insert(Pending {
+                    address: *address,
+                    request,
+                    deadline: Instant::now() + self.timeout,
+                    callback,
+                });
+                None
+            }
+        };
+        if let Some(callback) = duplicate {
+            // Responses could not be told apart, fail the new request
+            warn!("Request ID {:?} is already in use", id);
+            return callback(None);
+        }
+
+        if let Err(e) = self.socket.send_to(&data, address) {
+            warn!("Failed to send a request to {}: {}", address, e);
+            let pending = self.pending.lock().unwrap().remove(&id);
+            if let Some(pending) = pending {
+                (pending.callback)(None);
+            }
+        }
+    }
+
+    /// Fail requests with deadline before `now`, or all requests.
+    fn expire(&self, now: Option<Instant>) {
+        let expired: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            let ids: Vec<_> = pending
+                .iter()
+                .filter(|&(_, p)| now.map(|now| p.deadline <= now).unwrap_or(true))
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.iter().filter_map(|id| pending.remove(id)).collect()
+        };
+        // Callbacks are called without the lock held, they may send more
+        for pending in expired {
+            debug!("Request to {} timed out", pending.address);
+            (pending.callback)(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+    use std::sync::mpsc;
+    use std::time::{Duration, SystemTime};
+
+    use super::super::super::protocol::{Request, RequestPayload, ResponsePayload};
+    use super::super::super::service::VALUE_TTL;
+    use super::super::super::utils::test;
+    use super::super::super::{GenericNodeTable, KNodeTable, Service};
+    use super::super::Transport;
+    use super::UdpTransport;
+
+    type TestService =
+        Service<test::IdType, net::SocketAddr, KNodeTable<test::IdType, net::SocketAddr>, String>;
+
+    fn start(id: u8) -> (TestService, UdpTransport<test::TestProtocol>) {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let svc = Service::new_with_id(
+            KNodeTable::new(test::make_id(id)),
+            test::make_id(id),
+            address,
+        );
+        let transport = UdpTransport::new_with_timeout(
+            socket,
+            test::TestProtocol,
+            svc.handler(),
+            Duration::from_millis(500),
+        )
+        .unwrap();
+        (svc, transport)
+    }
+
+    #[test]
+    fn test_ping() {
+        let (svc1, transport1) = start(1);
+        let (svc2, _transport2) = start(2);
+
+        let request = Request {
+            caller: test::new_node_with_port(test::make_id(1), 1),
+            request_id: test::make_id(42),
+            payload: RequestPayload::Ping,
+        };
+        let (sender, receiver) = mpsc::channel();
+        transport1.send(svc2.address(), request, move |response| {
+            sender.send(response).unwrap();
+        });
+
+        let response = receiver.recv().unwrap().unwrap();
+        let responder = response.responder.unwrap();
+        assert_eq!(test::make_id(2), responder.id);
+        assert_eq!(*svc2.address(), responder.address);
+        match response.payload {
+            ResponsePayload::NoResult => (),
+            _ => panic!("wrong payload"),
+        }
+        // The caller address is taken from the datagram
+        let known = svc2.node_table().find(&test::make_id(1), 1);
+        assert_eq!(*svc1.address(), known[0].address);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (_svc, transport) = start(1);
+        // Bound, but nobody is listening
+        let silent = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let request = Request {
+            caller: test::new_node(test::make_id(1)),
+            request_id: test::make_id(42),
+            payload: RequestPayload::Ping,
+        };
+        let (sender, receiver) = mpsc::channel();
+        transport.send(&silent.local_addr().unwrap(), request, move |response| {
+            sender.send(response.is_none()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn test_lookup_and_get() {
+        let (mut svc1, transport1) = start(1);
+        let (mut svc2, _transport2) = start(2);
+        let (mut svc3, _transport3) = start(6);
+
+        let node2 = test::new_node_with_port(test::make_id(2), svc2.address().port());
+        let node3 = test::new_node_with_port(test::make_id(6), svc3.address().port());
+        svc1.node_table_mut().update(&node2);
+        svc2.node_table_mut().update(&node3);
+        svc3.stored_data_mut().put(
+            test::make_id(7),
+            "foobar".to_string(),
+            SystemTime::now() + VALUE_TTL,
+        );
+
+        let result = svc1.lookup_node(&transport1, &test::make_id(7));
+        let ids: Vec<_> = result.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(vec![test::make_id(6), test::make_id(2)], ids);
+
+        assert_eq!(
+            Some("foobar".to_string()),
+            svc1.get(&transport1, &test::make_id(7))
+        );
+    }
+}
@@ -0,0 +1,489 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! DHT node table as a binary tree of k-buckets.
+//!
+//! Unlike `KNodeTable`, the table starts with a single k-bucket covering the
+//! whole ID space. A full k-bucket is split in two by the next bit of IDs
+//! when it covers our own ID (see section 2.4 of the Kademlia paper), so
+//! only k-buckets that can hold nodes exist, and there is space for more
+//! than k nodes close to us. Optionally, buckets up to a given depth are
+//! split even if they do not cover our ID ("relaxed" splitting).
+
+use std::fmt::Debug;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use super::knodetable::{KBucket, BUCKET_SIZE, DEFAULT_HASH_SIZE, DEFAULT_NODE_TTL};
+use super::GenericId;
+use super::GenericNodeTable;
+use super::Node;
+
+enum Tree<TId, TAddr> {
+    Leaf(KBucket<TId, TAddr>),
+    /// Subtrees for the next bit of ID being 0 and 1.
+    Branch(Box<Tree<TId, TAddr>>, Box<Tree<TId, TAddr>>),
+}
+
+/// Kademlia node table with k-buckets split on demand.
+pub struct TreeNodeTable<TId, TAddr> {
+    this_id: TId,
+    hash_size: usize,
+    relaxed_depth: usize,
+    node_ttl: Option<Duration>,
+    root: Tree<TId, TAddr>,
+}
+
+/// Parameters of the tree shape.
+struct Shape<'a, TId: 'a> {
+    this_id: &'a TId,
+    hash_size: usize,
+    relaxed_depth: usize,
+}
+
+impl<'a, TId: GenericId> Shape<'a, TId> {
+    /// Bit of the ID deciding on the subtree at the given depth.
+    fn bit(&self, id: &TId, depth: usize) -> bool {
+        id.bit(self.hash_size - 1 - depth)
+    }
+
+    fn can_split(&self, depth: usize, own: bool) -> bool {
+        depth < self.hash_size && (own || depth < self.relaxed_depth)
+    }
+}
+
+impl<TId, TAddr> TreeNodeTable<TId, TAddr>
+where
+    TId: GenericId,
+    TAddr: Clone + Debug,
+{
+    /// Create a new node table.
+    ///
+    /// `this_id` -- ID of the current node (used to calculate metrics).
+    ///
+    /// The hash size is the size of `TId` if it is fixed, 160 otherwise.
+    pub fn new(this_id: TId) -> TreeNodeTable<TId, TAddr> {
+        let hash_size = TId::max_bits().unwrap_or(DEFAULT_HASH_SIZE);
+        TreeNodeTable::new_with_details(this_id, BUCKET_SIZE, hash_size)
+    }
+
+    pub fn new_with_details(
+        this_id: TId,
+        bucket_size: usize,
+        hash_size: usize,
+    ) -> TreeNodeTable<TId, TAddr> {
+        TreeNodeTable {
+            this_id,
+            hash_size,
+            relaxed_depth: 0,
+            node_ttl: Some(DEFAULT_NODE_TTL),
+            root: Tree::Leaf(KBucket::new(bucket_size)),
+        }
+    }
+
+    /// Split full k-buckets up to this depth even if they do not cover our ID.
+    ///
+    /// Defaults to 0, i.e. only k-buckets covering our ID are split.
+    pub fn set_relaxed_depth(&mut self, depth: usize) {
+        self.relaxed_depth = depth;
+    }
+    /// Set time after which nodes not heard from expire, `None` to disable.
+    ///
+    /// Defaults to 15 minutes.
+    pub fn set_node_ttl(&mut self, ttl: Option<Duration>) {
+        self.node_ttl = ttl;
+    }
+
+    /// ID of the current node.
+    pub fn this_id(&self) -> &TId {
+        &self.this_id
+    }
+    /// Number of k-buckets (leaves of the tree).
+    pub fn bucket_count(&self) -> usize {
+        self.leaves().len()
+    }
+
+    /// Pop nodes not heard from within the node TTL before `now`.
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<Node<TId, TAddr>> {
+        match self.node_ttl {
+            Some(ttl) => self
+                .leaves_mut()
+                .into_iter()
+                .flat_map(|b| b.pop_expired(now, ttl, |_, _| true))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn shape(&self) -> Shape<'_, TId> {
+        Shape {
+            this_id: &self.this_id,
+            hash_size: self.hash_size,
+            relaxed_depth: self.relaxed_depth,
+        }
+    }
+
+    /// K-bucket where the ID belongs.
+    fn leaf(&self, id: &TId) -> &KBucket<TId, TAddr> {
+        let shape = self.shape();
+        let mut tree = &self.root;
+        let mut depth = 0;
+        loop {
+            match *tree {
+                Tree::Leaf(ref bucket) => return bucket,
+                Tree::Branch(ref zero, ref one) => {
+                    tree = if shape.bit(id, depth) { one } else { zero };
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    fn leaf_mut(&mut self, id: &TId) -> &mut KBucket<TId, TAddr> {
+        let hash_size = self.hash_size;
+        let mut tree = &mut self.root;
+        let mut depth = 0;
+        loop {
+            match *tree {
+                Tree::Leaf(ref mut bucket) => return bucket,
+                Tree::Branch(ref mut zero, ref mut one) => {
+                    tree = if id.bit(hash_size - 1 - depth) {
+                        one
+                    } else {
+                        zero
+                    };
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    /// All k-buckets with bits of IDs leading to them.
+    fn leaves(&self) -> Vec<(&KBucket<TId, TAddr>, Vec<bool>)> {
+        fn collect<'a, TId, TAddr>(
+            tree: &'a Tree<TId, TAddr>,
+            path: &mut Vec<bool>,
+            result: &mut Vec<(&'a KBucket<TId, TAddr>, Vec<bool>)>,
+        ) {
+            match *tree {
+                Tree::Leaf(ref bucket) => result.push((bucket, path.clone())),
+                Tree::Branch(ref zero, ref one) => {
+                    path.push(false);
+                    collect(zero, path, result);
+                    let _ = path.pop();
+                    path.push(true);
+                    collect(one, path, result);
+                    let _ = path.pop();
+                }
+            }
+        }
+
+        let mut result = Vec::new();
+        collect(&self.root, &mut Vec::new(), &mut result);
+        result
+    }
+
+    fn leaves_mut(&mut self) -> Vec<&mut KBucket<TId, TAddr>> {
+        fn collect<'a, TId, TAddr>(
+            tree: &'a mut Tree<TId, TAddr>,
+            result: &mut Vec<&'a mut KBucket<TId, TAddr>>,
+        ) {
+            match *tree {
+                Tree::Leaf(ref mut bucket) => result.push(bucket),
+                Tree::Branch(ref mut zero, ref mut one) => {
+                    collect(zero, result);
+                    collect(one, result);
+                }
+            }
+        }
+
+        let mut result = Vec::new();
+        collect(&mut self.root, &mut result);
+        result
+    }
+}
+
+/// Insert the node into the subtree, splitting its k-bucket if needed.
+///
+/// `own` is whether the subtree covers our own ID.
+fn insert<TId, TAddr>(
+    tree: &mut Tree<TId, TAddr>,
+    depth: usize,
+    own: bool,
+    node: &Node<TId, TAddr>,
+    now: Instant,
+    shape: &Shape<TId>,
+) -> bool
+where
+    TId: GenericId,
+    TAddr: Clone + Debug,
+{
+    match *tree {
+        Tree::Branch(ref mut zero, ref mut one) => {
+            let bit = shape.bit(&node.id, depth);
+            let own = own && bit == shape.bit(shape.this_id, depth);
+            let child = if bit { one } else { zero };
+            return insert(child, depth + 1, own, node, now, shape);
+        }
+        Tree::Leaf(ref mut bucket) => {
+            let full = bucket.data().len() == bucket.size()
+                && !bucket.data().iter().any(|x| x.id == node.id);
+            if !full || !shape.can_split(depth, own) {
+                return bucket.update_at(node, now);
+            }
+        }
+    }
+
+    debug!("Splitting k-bucket at depth {}", depth);
+    let bucket = match mem::replace(tree, Tree::Leaf(KBucket::new(1))) {
+        Tree::Leaf(bucket) => bucket,
+        Tree::Branch(..) => unreachable!(),
+    };
+    let (zero, one) = bucket.split(|id| shape.bit(id, depth));
+    *tree = Tree::Branch(Box::new(Tree::Leaf(zero)), Box::new(Tree::Leaf(one)));
+    insert(tree, depth, own, node, now, shape)
+}
+
+impl<TId, TAddr> GenericNodeTable<TId, TAddr> for TreeNodeTable<TId, TAddr>
+where
+    TId: GenericId,
+    TAddr: Clone + Debug + Sync + Send,
+{
+    fn random_id(&self) -> TId {
+        TId::gen(self.hash_size)
+    }
+
+    fn hash_size(&self) -> usize {
+        self.hash_size
+    }
+
+    fn bucket_size(&self) -> usize {
+        let mut tree = &self.root;
+        loop {
+            match *tree {
+                Tree::Branch(ref zero, _) => tree = zero,
+                Tree::Leaf(ref bucket) => return bucket.size(),
+            }
+        }
+    }
+
+    fn update(&mut self, node: &Node<TId, TAddr>) -> bool {
+        self.update_at(node, Instant::now())
+    }
+
+    fn update_at(&mut self, node: &Node<TId, TAddr>, now: Instant) -> bool {
+        assert!(node.id != self.this_id);
+        let shape = Shape {
+            this_id: &self.this_id,
+            hash_size: self.hash_size,
+            relaxed_depth: self.relaxed_depth,
+        };
+        insert(&mut self.root, 0, true, node, now, &shape)
+    }
+
+    fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
+        debug_assert!(count > 0);
+
+        let mut data_copy: Vec<_> = self.iter().cloned().collect();
+        data_copy.sort_by_key(|n| id.bitxor(&n.id));
+        data_copy.truncate(count);
+        data_copy
+    }
+
+    fn pop_oldest(&mut self, now: Instant) -> Vec<Node<TId, TAddr>> {
+        let mut result = self.pop_expired(now);
+        // For every k-bucket that rejected a newcomer or has a failing node, pop
+        // the worst, unless a node from it is being checked already.
+        result.extend(
+            self.leaves_mut()
+                .into_iter()
+                .filter(|b| b.needs_check())
+                .filter_map(|b| b.pop_oldest(now, |_, _| true)),
+        );
+        result
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>> {
+        self.leaf_mut(id).remove(id, Instant::now(), |_, _| true)
+    }
+
+    fn get(&self, id: &TId) -> Option<&Node<TId, TAddr>> {
+        self.leaf(id).data().iter().find(|n| n.id == *id)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Node<TId, TAddr>> + 'a> {
+        Box::new(
+            self.leaves()
+                .into_iter()
+                .flat_map(|(bucket, _)| bucket.data().iter()),
+        )
+    }
+
+    fn len(&self) -> usize {
+        self.leaves().iter().map(|&(b, _)| b.data().len()).sum()
+    }
+
+    fn record_response(&mut self, id: &TId, rtt: Duration, now: Instant) {
+        self.leaf_mut(id).record_response(id, rtt, now);
+    }
+
+    fn record_failure(&mut self, id: &TId) {
+        self.leaf_mut(id).record_failure(id);
+    }
+
+    fn record_lookup(&mut self, id: &TId, now: Instant) {
+        self.leaf_mut(id).record_lookup(now);
+    }
+
+    fn stale_buckets<R: Rng>(&self, now: Instant, interval: Duration, rng: &mut R) -> Vec<TId> {
+        let shape = self.shape();
+        self.leaves()
+            .into_iter()
+            .filter(|&(b, _)| b.last_lookup() + interval <= now)
+            .map(|(_, path)| {
+                // Fix the bits leading to the k-bucket in a random ID
+                let mut id = TId::gen_with(self.hash_size, rng);
+                for (depth, bit) in path.into_iter().enumerate() {
+                    if shape.bit(&id, depth) != bit {
+                        let index = self.hash_size - 1 - depth;
+                        id = id.diverge_at(index, &id);
+                    }
+                }
+                id
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+    use std::time::{Duration, Instant};
+
+    use super::super::utils::test;
+    use super::super::{GenericId, GenericNodeTable};
+    use super::TreeNodeTable;
+
+    type TestTable = TreeNodeTable<test::IdType, net::SocketAddr>;
+
+    fn ids(table: &TestTable) -> Vec<test::IdType> {
+        let mut result: Vec<_> = table.iter().map(|n| n.id.clone()).collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn test_new() {
+        let n = TreeNodeTable::<u64, ()>::new(42);
+        assert_eq!(64, n.hash_size());
+        assert_eq!(1, n.bucket_count());
+        assert!(n.is_empty());
+    }
+
+    #[test]
+    fn test_split_own_bucket() {
+        // 0b0000_0000, nodes from the other half do not cause splitting
+        let mut n: TestTable = TreeNodeTable::new_with_details(test::make_id(0), 2, 8);
+        assert!(n.update(&test::new_node(test::make_id(0b1000_0000))));
+        assert!(n.update(&test::new_node(test::make_id(0b1100_0000))));
+        assert_eq!(1, n.bucket_count());
+
+        // Bucket covering our ID is full, it is split
+        assert!(n.update(&test::new_node(test::make_id(0b0100_0000))));
+        assert_eq!(2, n.bucket_count());
+        // Far bucket is full and is not split
+        assert!(!n.update(&test::new_node(test::make_id(0b1110_0000))));
+        assert_eq!(2, n.bucket_count());
+
+        // More than k nodes close to us
+        for id in &[1, 2, 3, 4] {
+            assert!(n.update(&test::new_node(test::make_id(*id))));
+        }
+        assert_eq!(7, n.len());
+        assert_eq!(
+            vec![
+                test::make_id(1),
+                test::make_id(2),
+                test::make_id(3),
+                test::make_id(4),
+                test::make_id(0b0100_0000),
+                test::make_id(0b1000_0000),
+                test::make_id(0b1100_0000),
+            ],
+            ids(&n)
+        );
+        let closest: Vec<_> = n
+            .find(&test::make_id(0), 2)
+            .into_iter()
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(vec![test::make_id(1), test::make_id(2)], closest);
+    }
+
+    #[test]
+    fn test_relaxed_split() {
+        let mut n: TestTable = TreeNodeTable::new_with_details(test::make_id(0), 2, 8);
+        n.set_relaxed_depth(2);
+        for id in &[0b1000_0000, 0b1100_0000, 0b0000_0001, 0b1110_0000] {
+            assert!(n.update(&test::new_node(test::make_id(*id))));
+        }
+        // 1xxx_xxxx was split into 10xx_xxxx and 11xx_xxxx
+        assert_eq!(3, n.bucket_count());
+        // No split at depth 2
+        assert!(!n.update(&test::new_node(test::make_id(0b1111_0000))));
+        assert_eq!(3, n.bucket_count());
+    }
+
+    #[test]
+    fn test_get_remove() {
+        let mut n: TestTable = TreeNodeTable::new_with_details(test::make_id(0), 1, 8);
+        n.update(&test::new_node(test::make_id(0b1000_0000)));
+        assert!(!n.update(&test::new_node(test::make_id(0b1100_0000))));
+        assert!(n.contains(&test::make_id(0b1000_0000)));
+        assert!(n.get(&test::make_id(0b1100_0000)).is_none());
+        assert!(n.remove(&test::make_id(0b1000_0000)).is_some());
+        // Replacement was promoted
+        assert_eq!(vec![test::make_id(0b1100_0000)], ids(&n));
+        assert!(n.remove(&test::make_id(0)).is_none());
+    }
+
+    #[test]
+    fn test_pop_oldest() {
+        let mut n: TestTable = TreeNodeTable::new_with_details(test::make_id(0), 1, 8);
+        n.set_node_ttl(None);
+        n.update(&test::new_node(test::make_id(0b1000_0000)));
+        n.update(&test::new_node(test::make_id(0b1100_0000)));
+        let popped = n.pop_oldest(Instant::now());
+        assert_eq!(1, popped.len());
+        assert_eq!(test::make_id(0b1000_0000), popped[0].id);
+        assert_eq!(vec![test::make_id(0b1100_0000)], ids(&n));
+    }
+
+    #[test]
+    fn test_stale_buckets() {
+        let interval = Duration::from_secs(3600);
+        let mut n: TestTable = TreeNodeTable::new_with_details(test::make_id(0), 1, 8);
+        for id in &[0b1000_0000, 0b0100_0000, 0b0010_0000, 0b0001_0000] {
+            n.update(&test::new_node(test::make_id(*id)));
+        }
+        assert_eq!(4, n.bucket_count());
+
+        let later = Instant::now() + interval;
+        n.record_lookup(&test::make_id(0b0100_1111), later);
+        let stale = n.stale_buckets(later, interval, &mut rand::thread_rng());
+        assert_eq!(3, stale.len());
+        let mut bits: Vec<_> = stale.iter().map(|id| id.bits()).collect();
+        bits.sort();
+        // Buckets 000x_xxxx, 001x_xxxx and 1xxx_xxxx
+        assert!(bits[0] <= 5);
+        assert_eq!(vec![6, 8], bits[1..].to_vec());
+    }
+}
@@ -2,8 +2,16 @@
 
 #[cfg(test)]
 pub mod test {
+    use std::fmt;
     use std::net;
+    use std::str;
+    use std::time::Duration;
 
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    use super::super::protocol::{
+        Protocol, ProtocolError, Request, RequestPayload, Response, ResponsePayload,
+    };
     use super::super::Node;
 
     /*
@@ -16,7 +24,7 @@ pub mod test {
         vec![i]
     }
 
-    pub static ADDR: &'static str = "127.0.0.1:8008";
+    pub static ADDR: &str = "127.0.0.1:8008";
 
     pub fn new_node(id: IdType) -> Node<IdType, net::SocketAddr> {
         new_node_with_port(id, 8008)
@@ -24,11 +32,181 @@ pub mod test {
 
     pub fn new_node_with_port(id: IdType, port: u16) -> Node<IdType, net::SocketAddr> {
         Node {
-            id: id,
-            address: net::SocketAddr::V4(net::SocketAddrV4::new(
-                net::Ipv4Addr::new(127, 0, 0, 1),
-                port,
-            )),
+            id,
+            address: make_addr(port),
+        }
+    }
+
+    pub fn make_addr(port: u16) -> net::SocketAddr {
+        net::SocketAddr::V4(net::SocketAddrV4::new(
+            net::Ipv4Addr::new(127, 0, 0, 1),
+            port,
+        ))
+    }
+
+    /// Simple text protocol, values must not contain spaces.
+    pub struct TestProtocol;
+
+    fn format_node(node: &Node<IdType, net::SocketAddr>) -> String {
+        format!("{}@{}", node.id.to_hex(), node.address)
+    }
+
+    fn malformed<T: fmt::Debug>(e: T) -> ProtocolError {
+        ProtocolError::Malformed(format!("{:?}", e))
+    }
+
+    fn parse_id(s: &str) -> Result<IdType, ProtocolError> {
+        s.from_hex().map_err(malformed)
+    }
+
+    fn parse_node(s: &str) -> Result<Node<IdType, net::SocketAddr>, ProtocolError> {
+        let mut parts = s.split('@');
+        Ok(Node {
+            id: parse_id(parts.next().unwrap())?,
+            address: parts.next().unwrap_or("").parse().map_err(malformed)?,
+        })
+    }
+
+    fn split(data: &[u8], kind: &str, min_len: usize) -> Result<Vec<String>, ProtocolError> {
+        let words: Vec<_> = str::from_utf8(data)
+            .map_err(malformed)?
+            .split(' ')
+            .map(|w| w.to_string())
+            .collect();
+        if words.len() < min_len || words[0] != kind {
+            return Err(ProtocolError::Malformed(format!("expected {}", kind)));
+        }
+        Ok(words)
+    }
+
+    fn arg(words: &[String], index: usize) -> Result<&str, ProtocolError> {
+        match words.get(index) {
+            Some(word) => Ok(word),
+            None => Err(ProtocolError::Malformed("not enough arguments".to_string())),
+        }
+    }
+
+    impl Protocol for TestProtocol {
+        type Id = IdType;
+        type Addr = net::SocketAddr;
+        type Value = String;
+
+        fn parse_request(
+            &self,
+            data: &[u8],
+            _source: &net::SocketAddr,
+        ) -> Result<Request<IdType, net::SocketAddr, String>, ProtocolError> {
+            let words = split(data, "REQ", 4)?;
+            let payload = match &words[3][..] {
+                "PING" => RequestPayload::Ping,
+                "FIND_NODE" => RequestPayload::FindNode(parse_id(arg(&words, 4)?)?),
+                "FIND_VALUE" => RequestPayload::FindValue(parse_id(arg(&words, 4)?)?),
+                "STORE" => RequestPayload::Store(
+                    parse_id(arg(&words, 4)?)?,
+                    arg(&words, 5)?.to_string(),
+                    match words.get(6) {
+                        Some(ttl) => Some(Duration::from_secs(ttl.parse().map_err(malformed)?)),
+                        None => None,
+                    },
+                ),
+                other => return Err(ProtocolError::UnknownMethod(other.to_string())),
+            };
+            Ok(Request {
+                request_id: parse_id(&words[1])?,
+                caller: parse_node(&words[2])?,
+                payload,
+            })
+        }
+
+        fn format_response(&self, response: Response<IdType, net::SocketAddr, String>) -> Vec<u8> {
+            let payload = match response.payload {
+                ResponsePayload::NodesFound(nodes) => {
+                    let nodes: Vec<_> = nodes.iter().map(format_node).collect();
+                    format!("NODES {}", nodes.join(" "))
+                }
+                ResponsePayload::ValueFound(value) => format!("VALUE {}", value),
+                ResponsePayload::NoResult => "NONE".to_string(),
+                ResponsePayload::Error(code, message) => format!("ERROR {} {}", code, message),
+            };
+            format!(
+                "RES {} {} {}",
+                response.request.request_id.to_hex(),
+                response
+                    .responder
+                    .as_ref()
+                    .map_or("-".to_string(), format_node),
+                payload
+            )
+            .into_bytes()
+        }
+
+        fn format_request(
+            &self,
+            request: &Request<IdType, net::SocketAddr, String>,
+            _address: &net::SocketAddr,
+        ) -> Vec<u8> {
+            let payload = match request.payload {
+                RequestPayload::Ping => "PING".to_string(),
+                RequestPayload::FindNode(ref id) => format!("FIND_NODE {}", id.to_hex()),
+                RequestPayload::FindValue(ref id) => format!("FIND_VALUE {}", id.to_hex()),
+                RequestPayload::Store(ref id, ref value, None) => {
+                    format!("STORE {} {}", id.to_hex(), value)
+                }
+                RequestPayload::Store(ref id, ref value, Some(ttl)) => {
+                    format!("STORE {} {} {}", id.to_hex(), value, ttl.as_secs())
+                }
+            };
+            format!(
+                "REQ {} {} {}",
+                request.request_id.to_hex(),
+                format_node(&request.caller),
+                payload
+            )
+            .into_bytes()
+        }
+
+        fn response_id(&self, data: &[u8]) -> Option<IdType> {
+            let words = split(data, "RES", 2).ok()?;
+            parse_id(&words[1]).ok()
+        }
+
+        fn parse_response(
+            &self,
+            request: Request<IdType, net::SocketAddr, String>,
+            data: &[u8],
+            _source: &net::SocketAddr,
+        ) -> Result<Response<IdType, net::SocketAddr, String>, ProtocolError> {
+            let words = split(data, "RES", 4)?;
+            let payload = match &words[3][..] {
+                "NODES" => ResponsePayload::NodesFound(
+                    words[4..]
+                        .iter()
+                        .filter(|w| !w.is_empty())
+                        .map(|w| parse_node(w))
+                        .collect::<Result<_, _>>()?,
+                ),
+                "VALUE" => ResponsePayload::ValueFound(arg(&words, 4)?.to_string()),
+                "ERROR" => ResponsePayload::Error(
+                    arg(&words, 4)?.parse().map_err(malformed)?,
+                    words[5..].join(" "),
+                ),
+                "NONE" => ResponsePayload::NoResult,
+                other => return Err(ProtocolError::Malformed(other.to_string())),
+            };
+            Ok(Response {
+                request,
+                responder: match &words[2][..] {
+                    "-" => None,
+                    node => Some(parse_node(node)?),
+                },
+                payload,
+            })
+        }
+
+        fn format_error(&self, data: &[u8], error: &ProtocolError) -> Option<Vec<u8>> {
+            let words = split(data, "REQ", 2).ok()?;
+            let data = format!("RES {} - ERROR {} {}", words[1], error.code(), error);
+            Some(data.into_bytes())
         }
     }
 }